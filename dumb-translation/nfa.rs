@@ -55,6 +55,13 @@ use std::{
     sync::atomic::{AtomicI32, Ordering},
 };
 
+// The original program uses '.' as the concatenation operator in
+// postfix notation, so a '.' wildcard in the pattern can't also be
+// written as '.' there. re2post writes this byte for it instead.
+// It's a NUL byte, which can't otherwise show up in a pattern
+// since command line arguments are NUL terminated.
+const POSTFIX_ANY: u8 = 0;
+
 // Convert infix regexp re to postfix notation.
 // Insert . as explicit concatenation operator.
 // Returns `None` for invalid patterns.
@@ -125,17 +132,23 @@ fn re2post(re: &[u8]) -> Option<Vec<u8>> {
                 }
                 dst.push(byte);
             }
-            // Not handled in the original program.
-            // Since '.' is a meta character in the
-            // postfix syntax, it can result in UB.
-            // So we reject it here.
-            b'.' => return None,
+            // A literal NUL would be mistaken for
+            // a wildcard in the postfix syntax.
+            POSTFIX_ANY => return None,
             _ => {
                 if natom > 1 {
                     natom -= 1;
                     dst.push(b'.');
                 }
-                dst.push(byte);
+                // Not handled in the original program.
+                // Since '.' is a meta character in the
+                // postfix syntax, passing it through as
+                // is can result in UB.
+                if byte == b'.' {
+                    dst.push(POSTFIX_ANY);
+                } else {
+                    dst.push(byte);
+                }
                 natom += 1;
             }
         }
@@ -164,9 +177,11 @@ fn re2post(re: &[u8]) -> Option<Vec<u8>> {
 // Represents an NFA state plus zero or one or two arrows exiting.
 // if c == Match, no arrows out; matching state.
 // If c == Split, unlabeled arrows to out and out1 (if != NULL).
+// If c == Any, arrow labeled with every character to out.
 // If c < 256, labeled arrow with character c to out.
 const MATCH: i32 = 256;
 const SPLIT: i32 = 257;
+const ANY: i32 = 258;
 
 struct State {
     c: i32,
@@ -179,10 +194,6 @@ struct State {
 // atomic because it's trivial to do so and is safe.
 static NSTATE: AtomicI32 = AtomicI32::new(0);
 
-// matching state
-static mut MATCH_STATE: State =
-    State { c: MATCH, out: null_mut(), out1: null_mut(), lastlist: 0 };
-
 impl State {
     // Allocate and initialize State
     fn new(c: i32, out: *mut State, out1: *mut State) -> *mut State {
@@ -245,8 +256,20 @@ impl PtrList {
 }
 
 // Convert postfix regular expression to NFA.
-// Return start state.
-fn post2nfa(postfix: &[u8]) -> *mut State {
+// Return start state and matching state. Every state created is also
+// pushed onto states.
+//
+// The original has no need to remember every state, but we do:
+// resetting the list IDs on overflow means visiting each of them.
+fn post2nfa(
+    postfix: &[u8],
+    states: &mut Vec<*mut State>,
+) -> (*mut State, *mut State) {
+    let mut new_state = |c, out, out1| {
+        let s = State::new(c, out, out1);
+        states.push(s);
+        s
+    };
     let mut stack: Vec<Frag> = vec![];
     for &p in postfix.iter() {
         match p {
@@ -263,14 +286,14 @@ fn post2nfa(postfix: &[u8]) -> *mut State {
             b'|' => {
                 let e2 = stack.pop().unwrap();
                 let e1 = stack.pop().unwrap();
-                let s = State::new(SPLIT, e1.start, e2.start);
+                let s = new_state(SPLIT, e1.start, e2.start);
                 let list = unsafe { PtrList::append(e1.out, e2.out) };
                 stack.push(Frag::new(s, list));
             }
             // zero or one
             b'?' => {
                 let e = stack.pop().unwrap();
-                let s = State::new(SPLIT, e.start, null_mut());
+                let s = new_state(SPLIT, e.start, null_mut());
                 let list = unsafe {
                     PtrList::append(
                         e.out,
@@ -282,7 +305,7 @@ fn post2nfa(postfix: &[u8]) -> *mut State {
             // zero or more
             b'*' => {
                 let e = stack.pop().unwrap();
-                let s = State::new(SPLIT, e.start, null_mut());
+                let s = new_state(SPLIT, e.start, null_mut());
                 unsafe {
                     PtrList::patch(e.out, s);
                 }
@@ -292,15 +315,21 @@ fn post2nfa(postfix: &[u8]) -> *mut State {
             // one or more
             b'+' => {
                 let e = stack.pop().unwrap();
-                let s = State::new(SPLIT, e.start, null_mut());
+                let s = new_state(SPLIT, e.start, null_mut());
                 unsafe {
                     PtrList::patch(e.out, s);
                 }
                 let list = unsafe { PtrList::list1(addr_of_mut!((*s).out1)) };
                 stack.push(Frag::new(e.start, list));
             }
+            // any character
+            POSTFIX_ANY => {
+                let s = new_state(ANY, null_mut(), null_mut());
+                let list = unsafe { PtrList::list1(addr_of_mut!((*s).out)) };
+                stack.push(Frag::new(s, list));
+            }
             _ => {
-                let s = State::new(i32::from(p), null_mut(), null_mut());
+                let s = new_state(i32::from(p), null_mut(), null_mut());
                 let list = unsafe { PtrList::list1(addr_of_mut!((*s).out)) };
                 stack.push(Frag::new(s, list));
             }
@@ -315,12 +344,17 @@ fn post2nfa(postfix: &[u8]) -> *mut State {
     // reached.
     let e = stack.pop().unwrap();
     if !stack.is_empty() {
-        return null_mut();
+        return (null_mut(), null_mut());
     }
+    // The original uses a single global matching
+    // state. As a static mut, that would be unsound
+    // if matching were ever done from more than one
+    // thread. So each NFA gets its own instead.
+    let matchstate = new_state(MATCH, null_mut(), null_mut());
     unsafe {
-        PtrList::patch(e.out, addr_of_mut!(MATCH_STATE));
+        PtrList::patch(e.out, matchstate);
     }
-    e.start
+    (e.start, matchstate)
 }
 
 struct List {
@@ -330,19 +364,43 @@ struct List {
 
 static LIST_ID: AtomicI32 = AtomicI32::new(0);
 
+// Increment to a new list id.
+//
+// The original overflows int if enough searches are run, which is UB.
+// Here, the atomic would just wrap around, but then a stale lastlist
+// could eventually look current again. So on overflow, we reset every
+// state back to the starting condition instead.
+unsafe fn increment_list_id(states: &[*mut State]) {
+    let id = LIST_ID.load(Ordering::Acquire);
+    let id = match id.checked_add(1) {
+        Some(id) => id,
+        None => {
+            for &s in states {
+                (*s).lastlist = 0;
+            }
+            1
+        }
+    };
+    LIST_ID.store(id, Ordering::Release);
+}
+
 impl List {
     // Compute initial state list
-    unsafe fn start(&mut self, start: *mut State) -> &mut List {
+    unsafe fn start(
+        &mut self,
+        start: *mut State,
+        states: &[*mut State],
+    ) -> &mut List {
         self.n = 0;
-        LIST_ID.fetch_add(1, Ordering::AcqRel);
+        increment_list_id(states);
         self.add_state(start);
         self
     }
 
     // Check whether state list contains a match.
-    unsafe fn is_match(&mut self) -> bool {
+    fn is_match(&mut self, matchstate: *mut State) -> bool {
         for i in 0..self.n {
-            if self.s[i as usize] == addr_of_mut!(MATCH_STATE) {
+            if self.s[i as usize] == matchstate {
                 return true;
             }
         }
@@ -369,12 +427,17 @@ impl List {
 // Step the NFA from the states in clist
 // past the character c,
 // to create next NFA state set nlist.
-unsafe fn step(clist: &mut List, c: i32, nlist: &mut List) {
-    LIST_ID.fetch_add(1, Ordering::AcqRel);
+unsafe fn step(
+    clist: &mut List,
+    c: i32,
+    nlist: &mut List,
+    states: &[*mut State],
+) {
+    increment_list_id(states);
     nlist.n = 0;
     for i in 0..clist.n {
         let s = clist.s[i as usize];
-        if (*s).c == c {
+        if (*s).c == c || (*s).c == ANY {
             nlist.add_state((*s).out);
         }
     }
@@ -385,15 +448,17 @@ unsafe fn r#match(
     l1: &mut List,
     l2: &mut List,
     start: *mut State,
+    matchstate: *mut State,
+    states: &[*mut State],
     s: &[u8],
 ) -> bool {
-    let clist = l1.start(start);
+    let clist = l1.start(start, states);
     let nlist = l2;
     for &byte in s.iter() {
-        step(clist, i32::from(byte), nlist);
+        step(clist, i32::from(byte), nlist, states);
         std::mem::swap(clist, nlist);
     }
-    clist.is_match()
+    clist.is_match(matchstate)
 }
 
 fn main() -> ExitCode {
@@ -402,9 +467,18 @@ fn main() -> ExitCode {
         eprintln!("usage: nfa regexp string...");
         return ExitCode::FAILURE;
     }
+    // Debug builds can start the list ID just short of overflowing, so
+    // that the tests can check that it gets reset correctly.
+    #[cfg(debug_assertions)]
+    if let Some(id) = std::env::var_os("NFA_LIST_ID") {
+        let Some(id) = id.to_str().and_then(|id| id.parse().ok()) else {
+            eprintln!("NFA_LIST_ID is not a valid list ID");
+            return ExitCode::FAILURE;
+        };
+        LIST_ID.store(id, Ordering::Release);
+    }
 
-    let Ok(pattern) = argv.by_ref().skip(1).next().unwrap().into_string()
-    else {
+    let Ok(pattern) = argv.by_ref().nth(1).unwrap().into_string() else {
         eprintln!("pattern is invalid UTF-8");
         return ExitCode::FAILURE;
     };
@@ -412,7 +486,8 @@ fn main() -> ExitCode {
         eprintln!("bad regexp {pattern}");
         return ExitCode::FAILURE;
     };
-    let start = post2nfa(&post);
+    let mut states = vec![];
+    let (start, matchstate) = post2nfa(&post, &mut states);
     if start.is_null() {
         eprintln!("error in post2nfa {pattern}");
         return ExitCode::FAILURE;
@@ -426,7 +501,16 @@ fn main() -> ExitCode {
             eprintln!("haystack is invalid UTF-8");
             return ExitCode::FAILURE;
         };
-        if unsafe { r#match(&mut l1, &mut l2, start, haystack.as_bytes()) } {
+        if unsafe {
+            r#match(
+                &mut l1,
+                &mut l2,
+                start,
+                matchstate,
+                &states,
+                haystack.as_bytes(),
+            )
+        } {
             println!("{haystack}");
         }
     }
@@ -1,12 +1,17 @@
 // This version of the C program tries to represent something approximating an
-// "idiomatic" Rust program. A more precise description might be "idiomatic
-// but also simplistic." That is, like the previous translations, we do try
-// to retain the character of the original. If the essence of the original
-// program is to be small and digestible implementation of a Thompson NFA
-// simulation, then we attempt to retain that essence here. For example, we
-// don't bother with enhancing the parser to improve failure modes because that
-// would distract from the primary goal: demonstrate the Thompson NFA matching
-// algorithm.
+// "idiomatic" Rust program. It started out like the previous translations,
+// retaining the character of the original: a small and digestible
+// implementation of a Thompson NFA simulation. It has since grown well past
+// that. The parser handles classes, escapes, counted repetition, anchors and
+// flags, and reports every problem in a bad pattern along with where it is.
+// Around the simulation, the `Matcher` grew the searches you'd expect from a
+// regex library: match spans and captures, iterating, replacing, splitting,
+// searching a reader one chunk at a time, and sharing one NFA between threads.
+//
+// The simulation at the core of it is still the one from the original program,
+// and it's still the place to start reading: `NFA::post2nfa` builds the NFA,
+// and `Scratch::step` and `Scratch::add_state_to_next` run it. Everything else
+// is built on top of those, without changing how they work.
 //
 // Instead, the principal change we make here is to replace the use of pointers
 // to states with "handles" or indices to states. So instead of a `*State` or a
@@ -52,13 +57,14 @@
 // other changes I made as well:
 //
 // * I got rid of all shared global mutable state. Instead, it's encapsulated
-// in a new type called `Matcher`. This works much more nicely for Rust because
-// Rust forces shared global mutable state to be safe even in the face of
-// multiple threads. Such a thing would be an unnecessary complication in a
-// pedagogical single threaded program.
+// in a new type called `Scratch`, which a `Matcher` pairs with an NFA. This
+// works much more nicely for Rust because Rust forces shared global mutable
+// state to be safe even in the face of multiple threads. Keeping it separate
+// from the NFA also means that many threads can search with one NFA at once,
+// each with scratch space of its own, which is what a `MatcherPool` does.
 //
 // * I moved the "last list ID" optimization off of the `State` type and into
-// the `Matcher` type. The original program puts the last list ID on the
+// the `Scratch` type. The original program puts the last list ID on the
 // `State` type itself, and as a result, is the only part of the NFA that is
 // mutable after it's built. Because this program uses handles, there's no
 // reason why we couldn't do that here too. That is, the borrow checker doesn't
@@ -73,265 +79,3477 @@
 // * The `PtrList` stuff is replaced with a simpler but perhaps more wasteful
 // `Vec` of state handles to patch. The original program embeds a linked list
 // into the as-yet-unused parts of a `State`. It's not clear how this could be
-// done with handles, and since performance isn't a concern and things like
-// `Vec` are easily usable (unlike in C), I chose to just use a more explicit
-// representation.
+// done with handles, and since patching only happens while building the NFA,
+// never while searching, and things like `Vec` are easily usable (unlike in
+// C), I chose to just use a more explicit representation.
+//
+// * The postfix form of a pattern is a sequence of `Token`s instead of bytes.
+// The original program uses `.` as its concatenation operator, which means
+// `.` can't also be the "any byte" wildcard. A sum type keeps operators and
+// atoms apart.
 
 #![forbid(unsafe_code)]
 
+use std::{
+    collections::{HashMap, TryReserveError, VecDeque},
+    ops::Range,
+    sync::{Arc, Mutex, OnceLock, PoisonError},
+};
+
+// A single element of a postfix pattern.
+#[derive(Clone, Copy, Eq, PartialEq)]
+enum Token {
+    // match one particular byte
+    Byte(u8),
+    // match any byte
+    Any,
+    // match any UTF-8 encoded codepoint, except for '\n'
+    // if newline is false
+    AnyChar { newline: bool },
+    // match any byte in the set, or any byte not in
+    // it if negated is true
+    Class { set: ByteSet, negated: bool },
+    // match the empty string at the start of the haystack
+    StartText,
+    // match the empty string at the end of the haystack
+    EndText,
+    // match the empty string at the start of the haystack
+    // or right after a '\n'
+    StartLine,
+    // match the empty string at the end of the haystack
+    // or right before a '\n'
+    EndLine,
+    // match the empty string between a word byte and a non-word
+    // byte, or anywhere else if negate is true
+    WordBoundary { negate: bool },
+    // match the empty string
+    Empty,
+    // concatenate the top two expressions
+    Concat,
+    // alternate between the top two expressions
+    Alternate,
+    // zero or one of the top expression
+    ZeroOrOne,
+    // zero or more of the top expression
+    ZeroOrMore,
+    // one or more of the top expression
+    OneOrMore,
+    // record where the top expression's match starts and ends
+    // as the capture group with the given index
+    Group(u32),
+    // between min and max of the top expression, with no
+    // upper limit if max is None. These only come out of
+    // re2post, and expand_repetitions rewrites them in
+    // terms of the other tokens before an NFA is built.
+    Repeat { min: u32, max: Option<u32> },
+}
+
+// A set of bytes, with one bit for each possible byte.
+#[derive(Clone, Copy, Eq, PartialEq)]
+struct ByteSet([u64; 4]);
+
+impl ByteSet {
+    fn new() -> ByteSet {
+        ByteSet([0; 4])
+    }
+
+    fn insert(&mut self, byte: u8) {
+        self.0[usize::from(byte / 64)] |= 1 << (byte % 64);
+    }
+
+    fn remove(&mut self, byte: u8) {
+        self.0[usize::from(byte / 64)] &= !(1 << (byte % 64));
+    }
+
+    fn contains(&self, byte: u8) -> bool {
+        self.0[usize::from(byte / 64)] & (1 << (byte % 64)) != 0
+    }
+
+    fn insert_range(&mut self, start: u8, end: u8) {
+        for byte in start..=end {
+            self.insert(byte);
+        }
+    }
+
+    // add every byte in the other set to this one
+    fn union(&mut self, other: &ByteSet) {
+        for (bits, other) in self.0.iter_mut().zip(other.0) {
+            *bits |= other;
+        }
+    }
+
+    // remove every byte that isn't in the other set from this one
+    fn intersect(&mut self, other: &ByteSet) {
+        for (bits, other) in self.0.iter_mut().zip(other.0) {
+            *bits &= other;
+        }
+    }
+
+    // return the set of bytes not in this set
+    fn complement(&self) -> ByteSet {
+        ByteSet(self.0.map(|bits| !bits))
+    }
+
+    // return the bytes in this set, in ascending order
+    fn iter(&self) -> impl Iterator<Item = u8> + '_ {
+        (0..=255).filter(|&byte| self.contains(byte))
+    }
+
+    // return the smallest list of inclusive ranges
+    // covering this set, in ascending order
+    fn ranges(&self) -> Vec<(u8, u8)> {
+        let mut ranges: Vec<(u8, u8)> = vec![];
+        for byte in self.iter() {
+            match ranges.last_mut() {
+                Some((_, end)) if u16::from(*end) + 1 == u16::from(byte) => {
+                    *end = byte;
+                }
+                _ => ranges.push((byte, byte)),
+            }
+        }
+        ranges
+    }
+
+    // return the offset of the first byte of the haystack at or
+    // after at that's in this set, or the haystack's length if
+    // there isn't one
+    fn find_in(&self, haystack: &[u8], at: usize) -> usize {
+        haystack[at..]
+            .iter()
+            .position(|&byte| self.contains(byte))
+            .map_or(haystack.len(), |i| at + i)
+    }
+
+    // return the members of this set as they'd be written
+    // in a class, with ranges where they save space
+    fn describe(&self) -> String {
+        let mut members = vec![];
+        for (start, end) in self.ranges() {
+            members.push(start);
+            if end > start {
+                if end - start > 1 {
+                    members.push(b'-');
+                }
+                members.push(end);
+            }
+        }
+        members.escape_ascii().to_string()
+    }
+}
+
+// The ways in which a pattern can be invalid.
+#[derive(Clone, Copy, Debug)]
+enum ParseErrorKind {
+    // the pattern is longer than Limits::max_pattern_len
+    TooLong,
+    // parens are nested deeper than Limits::max_paren_depth
+    TooDeep,
+    // a '(' with no matching ')'
+    UnclosedParen,
+    // a ')' with no matching '('
+    UnopenedParen,
+    // a group with nothing in it, i.e., `()`
+    EmptyGroup,
+    // a repetition operator with nothing before it to repeat
+    MissingOperand,
+    // a '[' with no matching ']'
+    UnclosedClass,
+    // a class with no members, i.e., `[]`
+    EmptyClass,
+    // a class range like `z-a` whose end comes before its start
+    ClassRangeOutOfOrder,
+    // a '\\' with nothing after it
+    TrailingBackslash,
+    // an escape of a byte with no special meaning
+    UnrecognizedEscape,
+    // a `\\x` escape without two hex digits after it
+    BadHexEscape,
+    // a '{' with no matching '}'
+    UnclosedRepetition,
+    // a repetition like `{,2}` without a minimum count
+    MissingRepetitionCount,
+    // something other than counts inside a repetition
+    InvalidRepetition,
+    // a repetition count bigger than MAX_REPEAT
+    RepetitionCountTooBig,
+    // a repetition like `{2,1}` whose maximum is less than its minimum
+    RepetitionRangeOutOfOrder,
+    // in postfix, a '.' or '|' without two expressions before it
+    MissingOperands,
+    // in postfix, expressions left over at the end, like in `ab`
+    MissingOperator,
+    // a byte in a flag group like `(?i)` that isn't a known flag
+    UnrecognizedFlag,
+}
+
+impl std::fmt::Display for ParseErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let message = match *self {
+            ParseErrorKind::TooLong => "pattern is too long",
+            ParseErrorKind::TooDeep => "parens are nested too deeply",
+            ParseErrorKind::UnclosedParen => "unclosed '('",
+            ParseErrorKind::UnopenedParen => "unopened ')'",
+            ParseErrorKind::EmptyGroup => "empty group",
+            ParseErrorKind::MissingOperand => {
+                "repetition operator is missing an operand"
+            }
+            ParseErrorKind::UnclosedClass => "unclosed '['",
+            ParseErrorKind::EmptyClass => "empty class",
+            ParseErrorKind::ClassRangeOutOfOrder => {
+                "class range is out of order"
+            }
+            ParseErrorKind::TrailingBackslash => "'\\' at end of pattern",
+            ParseErrorKind::UnrecognizedEscape => "unrecognized escape",
+            ParseErrorKind::BadHexEscape => "'\\x' needs two hex digits",
+            ParseErrorKind::UnclosedRepetition => "unclosed '{'",
+            ParseErrorKind::MissingRepetitionCount => {
+                "missing repetition count"
+            }
+            ParseErrorKind::InvalidRepetition => "invalid repetition",
+            ParseErrorKind::RepetitionCountTooBig => {
+                "repetition count is too big"
+            }
+            ParseErrorKind::RepetitionRangeOutOfOrder => {
+                "repetition range is out of order"
+            }
+            ParseErrorKind::MissingOperands => {
+                "binary operator is missing an operand"
+            }
+            ParseErrorKind::MissingOperator => {
+                "expressions are left without an operator to join them"
+            }
+            ParseErrorKind::UnrecognizedFlag => "unrecognized flag",
+        };
+        write!(f, "{message}")
+    }
+}
+
+// A problem found in a pattern, along with the
+// byte offset in the pattern where it was found.
+#[derive(Debug)]
+struct ParseError {
+    kind: ParseErrorKind,
+    offset: usize,
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "at offset {}: {}", self.offset, self.kind)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+// Parse a bracketed class like `[abc]` or `[a-z0-9]`, given
+// the bytes just after its opening '['. This consumes
+// everything up to and including the closing ']'. Each byte
+// inside the brackets is a member of the class, except that
+// `x-y` means every byte from x to y. A '-' that can't be part
+// of a range, i.e., at the start or end, is just a '-'. There
+// must be at least one member.
+//
+// A '^' right after the '[' negates the class, so that it
+// matches every byte that isn't listed. Anywhere else, '^'
+// is just a member. This returns the listed members along
+// with whether the class is negated.
+//
+// A Perl class like `\d` adds all of its members, but it
+// can't be either end of a range.
+fn parse_class(
+    it: &mut std::slice::Iter<'_, u8>,
+) -> Result<(ByteSet, bool), ParseErrorKind> {
+    let negated = it.as_slice().first() == Some(&b'^');
+    if negated {
+        it.next();
+    }
+    let mut set = ByteSet::new();
+    let mut empty = true;
+    loop {
+        let start = match it.next() {
+            None => return Err(ParseErrorKind::UnclosedClass),
+            Some(b']') if empty => return Err(ParseErrorKind::EmptyClass),
+            Some(b']') => return Ok((set, negated)),
+            Some(b'\\') => match perl_class(it) {
+                Some((perl, false)) => {
+                    set.union(&perl);
+                    empty = false;
+                    continue;
+                }
+                Some((perl, true)) => {
+                    set.union(&perl.complement());
+                    empty = false;
+                    continue;
+                }
+                None => parse_escape(it)?,
+            },
+            Some(&byte) => byte,
+        };
+        match *it.as_slice() {
+            [b'-', end, ..] if end != b']' => {
+                it.next();
+                let end = match it.next() {
+                    Some(b'\\') => parse_escape(it)?,
+                    _ => end,
+                };
+                if end < start {
+                    return Err(ParseErrorKind::ClassRangeOutOfOrder);
+                }
+                set.insert_range(start, end);
+            }
+            _ => set.insert(start),
+        }
+        empty = false;
+    }
+}
+
+// If the bytes just after a '\' are a Perl class like `\d`,
+// consume it and return its set of bytes, along with whether
+// it's negated. These are `\d` for digits, `\w` for word bytes,
+// i.e., `[0-9A-Za-z_]`, and `\s` for whitespace. An upper case
+// letter negates the class, so `\D` matches every byte that
+// isn't a digit.
+fn perl_class(it: &mut std::slice::Iter<'_, u8>) -> Option<(ByteSet, bool)> {
+    let &byte = it.as_slice().first()?;
+    let mut set = ByteSet::new();
+    match byte.to_ascii_lowercase() {
+        b'd' => set.insert_range(b'0', b'9'),
+        b'w' => {
+            set.insert_range(b'0', b'9');
+            set.insert_range(b'A', b'Z');
+            set.insert_range(b'a', b'z');
+            set.insert(b'_');
+        }
+        b's' => {
+            for &space in b" \t\n\r\x0b\x0c" {
+                set.insert(space);
+            }
+        }
+        _ => return None,
+    }
+    it.next();
+    Some((set, byte.is_ascii_uppercase()))
+}
+
+// Parse an escape outside of a class, given the bytes just
+// after its '\', and return the atom it stands for. On top of
+// what parse_escape handles, this includes Perl classes like
+// `\d` and the word boundary assertions `\b` and `\B`.
+fn parse_escaped_atom(
+    it: &mut std::slice::Iter<'_, u8>,
+) -> Result<Token, ParseErrorKind> {
+    if let Some(&byte @ (b'b' | b'B')) = it.as_slice().first() {
+        it.next();
+        return Ok(Token::WordBoundary { negate: byte == b'B' });
+    }
+    if let Some((set, negated)) = perl_class(it) {
+        return Ok(Token::Class { set, negated });
+    }
+    parse_escape(it).map(Token::Byte)
+}
+
+// Parse an escape like `\*`, given the bytes just after
+// its '\', and return the byte it stands for. Bytes with a
+// special meaning somewhere can be escaped, and so can
+// `\n`, `\t` and `\r` and a hex byte like `\x41`, which
+// must have exactly two hex digits. Other escapes are
+// rejected, so that they can be given a meaning of their
+// own later without changing what existing patterns match.
+fn parse_escape(
+    it: &mut std::slice::Iter<'_, u8>,
+) -> Result<u8, ParseErrorKind> {
+    match it.next() {
+        None => Err(ParseErrorKind::TrailingBackslash),
+        Some(&byte) if b"\\.*+?|()[]^$-{}".contains(&byte) => Ok(byte),
+        Some(b'n') => Ok(b'\n'),
+        Some(b't') => Ok(b'\t'),
+        Some(b'r') => Ok(b'\r'),
+        Some(b'x') => {
+            let mut hex = || {
+                let &digit = it.as_slice().first()?;
+                let value = char::from(digit).to_digit(16)?;
+                it.next();
+                Some(value as u8)
+            };
+            match (hex(), hex()) {
+                (Some(hi), Some(lo)) => Ok(hi << 4 | lo),
+                _ => Err(ParseErrorKind::BadHexEscape),
+            }
+        }
+        Some(_) => Err(ParseErrorKind::UnrecognizedEscape),
+    }
+}
+
+// The most times a counted repetition like `a{2,5}` may
+// repeat its operand.
+const MAX_REPEAT: u32 = 1000;
+
+// Parse the counts of a repetition like `{2}`, `{2,}` or
+// `{2,5}`, given the bytes just after its opening '{'. This
+// consumes everything up to and including the closing '}',
+// and returns the minimum and maximum counts. A maximum of
+// None means there is no upper limit.
+fn parse_counts(
+    it: &mut std::slice::Iter<'_, u8>,
+) -> Result<(u32, Option<u32>), ParseErrorKind> {
+    let min = match parse_count(it)? {
+        Some(min) => min,
+        None if it.as_slice().is_empty() => {
+            return Err(ParseErrorKind::UnclosedRepetition)
+        }
+        None => return Err(ParseErrorKind::MissingRepetitionCount),
+    };
+    let max = match it.next() {
+        Some(b'}') => Some(min),
+        Some(b',') => {
+            let max = parse_count(it)?;
+            match it.next() {
+                Some(b'}') => max,
+                Some(_) => return Err(ParseErrorKind::InvalidRepetition),
+                None => return Err(ParseErrorKind::UnclosedRepetition),
+            }
+        }
+        Some(_) => return Err(ParseErrorKind::InvalidRepetition),
+        None => return Err(ParseErrorKind::UnclosedRepetition),
+    };
+    match max {
+        Some(max) if max < min => {
+            Err(ParseErrorKind::RepetitionRangeOutOfOrder)
+        }
+        _ => Ok((min, max)),
+    }
+}
+
+// Parse a decimal count in a repetition, if there is one.
+fn parse_count(
+    it: &mut std::slice::Iter<'_, u8>,
+) -> Result<Option<u32>, ParseErrorKind> {
+    let mut count = None;
+    while let Some(&byte @ b'0'..=b'9') = it.as_slice().first() {
+        it.next();
+        let n: u32 = count.unwrap_or(0);
+        count =
+            Some(n.saturating_mul(10).saturating_add(u32::from(byte - b'0')));
+    }
+    match count {
+        Some(n) if n > MAX_REPEAT => {
+            Err(ParseErrorKind::RepetitionCountTooBig)
+        }
+        _ => Ok(count),
+    }
+}
+
+// Limits on the size of a pattern and of the NFA built
+// from it. The defaults are arbitrary, but generous enough
+// for any pattern someone would type by hand.
+#[derive(Clone, Copy)]
+struct Limits {
+    // the longest pattern re2post accepts, in bytes
+    max_pattern_len: usize,
+    // how deeply parens may be nested
+    max_paren_depth: usize,
+    // the most states an NFA may have
+    max_states: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            // The original program converts a pattern into
+            // a buffer of 8000 bytes, and the postfix form
+            // of a pattern can be up to twice as long.
+            max_pattern_len: 8000 / 2 - 1,
+            max_paren_depth: 100,
+            // by default, the only limit is what a StateID
+            // can identify
+            max_states: StateID::MAX as usize,
+        }
+    }
+}
+
+// The number of bytes in a UTF-8 encoded codepoint that starts
+// with the given byte, assuming it's valid.
+fn utf8_len(byte: u8) -> usize {
+    match byte {
+        0xC0..=0xDF => 2,
+        0xE0..=0xEF => 3,
+        0xF0..=0xF7 => 4,
+        _ => 1,
+    }
+}
+
+// Parse a pattern that is already in postfix notation, for
+// --postfix. This skips re2post entirely: '.' concatenates the
+// two expressions before it and '|' alternates them, while '*',
+// '+' and '?' apply to the one expression before them. Every
+// other byte matches itself, so `ab.c|` is the postfix for
+// `ab|c`. Since '.' is taken, there's no way to write the
+// wildcard, and there are no classes, escapes, anchors, groups
+// or counted repetitions either.
+//
+// Like in re2post, an empty pattern matches the empty string.
+// Otherwise, everything has to be joined into one expression.
+fn parse_postfix(
+    re: &[u8],
+    limits: &Limits,
+) -> Result<Vec<Token>, ParseError> {
+    let err = |kind, offset| ParseError { kind, offset };
+    if re.is_empty() {
+        return Ok(vec![Token::Empty]);
+    }
+    if re.len() > limits.max_pattern_len {
+        return Err(err(ParseErrorKind::TooLong, limits.max_pattern_len));
+    }
+    let mut dst = Vec::with_capacity(re.len());
+    // the number of expressions so far that no operator has taken
+    let mut depth = 0;
+    for (i, &byte) in re.iter().enumerate() {
+        let (token, operands) = match byte {
+            b'.' => (Token::Concat, 2),
+            b'|' => (Token::Alternate, 2),
+            b'*' => (Token::ZeroOrMore, 1),
+            b'+' => (Token::OneOrMore, 1),
+            b'?' => (Token::ZeroOrOne, 1),
+            _ => (Token::Byte(byte), 0),
+        };
+        if depth < operands {
+            let kind = if operands == 2 {
+                ParseErrorKind::MissingOperands
+            } else {
+                ParseErrorKind::MissingOperand
+            };
+            return Err(err(kind, i));
+        }
+        depth = depth - operands + 1;
+        dst.push(token);
+    }
+    if depth > 1 {
+        return Err(err(ParseErrorKind::MissingOperator, re.len()));
+    }
+    Ok(dst)
+}
+
+// The options that a flag group at the start of a pattern can
+// turn on, for just that pattern. They start out as whatever was
+// given on the command line, except for ignore_case, which is
+// left to BuildOptions since case is folded after parsing.
+#[derive(Clone, Copy)]
+struct Flags {
+    // letters match either case, which NFA::ignore_case sees to
+    ignore_case: bool,
+    // `^` and `$` match at the start and end of every line too
+    multi_line: bool,
+    // `.` matches '\n' like any other byte
+    dot_matches_newline: bool,
+}
+
+// Find the flag group that may come at the very start of a
+// pattern, like `(?i)`. Each flag turns on one of the options in
+// Flags: `i` is ignore_case, `m` is multi_line and `s` is
+// dot_matches_newline. They can be combined, as in `(?ms)`. A
+// flag group anywhere else isn't recognized, so re2post rejects
+// it like any other '?' with nothing before it to repeat.
+//
+// This returns how many bytes the flag group takes, 0 when there
+// isn't one, and the flags with those it has turned on.
+fn inline_flags(
+    re: &[u8],
+    mut flags: Flags,
+) -> Result<(usize, Flags), ParseError> {
+    let err = |kind, offset| ParseError { kind, offset };
+    if !re.starts_with(b"(?") {
+        return Ok((0, flags));
+    }
+    for (i, &byte) in re.iter().enumerate().skip(2) {
+        match byte {
+            b'i' => flags.ignore_case = true,
+            b'm' => flags.multi_line = true,
+            b's' => flags.dot_matches_newline = true,
+            b')' => return Ok((i + 1, flags)),
+            _ => return Err(err(ParseErrorKind::UnrecognizedFlag, i)),
+        }
+    }
+    Err(err(ParseErrorKind::UnclosedParen, 0))
+}
+
 // Convert infix regexp re to postfix notation.
-// Insert . as explicit concatenation operator.
-// Returns `None` for invalid patterns.
-fn re2post(re: &[u8]) -> Option<Vec<u8>> {
+// Insert Token::Concat as explicit concatenation operator.
+// Returns the first problem found for invalid patterns.
+//
+// When utf8 is true, `.` matches one whole UTF-8 encoded codepoint
+// instead of one byte, and a codepoint in the pattern that takes more
+// than one byte is a single atom, so `é+` repeats all of `é`. Classes
+// are still sets of bytes.
+//
+// An empty group `()` is rejected, unless lenient is true, in which
+// case it matches the empty string like `(|)` does. So `a()b` is the
+// same as `ab`.
+//
+// With flags.multi_line, `^` and `$` also match right after and
+// right before a '\n', and without flags.dot_matches_newline, `.`
+// doesn't match '\n'. flags.ignore_case is left for the NFA.
+//
+// Along with the postfix pattern, this returns how many groups the
+// pattern has, not counting group 0.
+fn re2post(
+    re: &[u8],
+    limits: &Limits,
+    utf8: bool,
+    lenient: bool,
+    flags: Flags,
+) -> Result<(Vec<Token>, usize), ParseError> {
     struct Paren {
+        offset: usize,
         nalt: i32,
         natom: i32,
+        group: u32,
     }
+    let err = |kind, offset| ParseError { kind, offset };
 
-    // Unlike the original program, we reject the
-    // empty pattern as invalid. This avoids an
-    // error case in post2nfa.
+    // The original program produces an empty postfix
+    // pattern here, which post2nfa can't do anything
+    // sensible with. There's no byte we could use to
+    // write an empty atom, but we have a token for it.
     if re.is_empty() {
-        return None;
+        return Ok((vec![Token::Empty], 0));
     }
-    if re.len() >= 8000 / 2 {
-        return None;
+    if re.len() > limits.max_pattern_len {
+        return Err(err(ParseErrorKind::TooLong, limits.max_pattern_len));
     }
     let (mut nalt, mut natom) = (0, 0);
     let mut paren = vec![];
     let mut dst = vec![];
-    for &byte in re.iter() {
+    let mut quantified = false;
+    // Every group captures. They're numbered by their open
+    // paren, starting at 1, since group 0 is the whole match.
+    let mut ngroups = 0;
+    let mut it = re.iter();
+    while let Some(&byte) = it.next() {
+        let i = re.len() - it.len() - 1;
+        // A '+' right after a quantifier makes it possessive
+        // in engines that backtrack, i.e., `a*+` won't give
+        // back any of the a's it matched, so `a*+a` can never
+        // match. A Thompson NFA has no way to refuse to give
+        // bytes back, so we accept the syntax for
+        // compatibility but treat the quantifier as a greedy
+        // one: here, `a*+a` matches aa.
+        if byte == b'+' && std::mem::take(&mut quantified) {
+            continue;
+        }
+        quantified = matches!(byte, b'*' | b'+' | b'?' | b'{');
         match byte {
             b'(' => {
                 if natom > 1 {
                     natom -= 1;
-                    dst.push(b'.');
+                    dst.push(Token::Concat);
                 }
-                if paren.len() >= 100 {
-                    return None;
+                if paren.len() >= limits.max_paren_depth {
+                    return Err(err(ParseErrorKind::TooDeep, i));
                 }
-                paren.push(Paren { nalt, natom });
+                ngroups += 1;
+                paren.push(Paren { offset: i, nalt, natom, group: ngroups });
                 nalt = 0;
                 natom = 0;
             }
             b'|' => {
+                // An empty alternative matches the empty string.
                 if natom == 0 {
-                    return None;
+                    dst.push(Token::Empty);
+                    natom += 1;
                 }
                 natom -= 1;
                 while natom > 0 {
-                    dst.push(b'.');
+                    dst.push(Token::Concat);
                     natom -= 1;
                 }
                 nalt += 1;
             }
             b')' => {
-                let p = paren.pop()?;
+                let p = paren
+                    .pop()
+                    .ok_or(err(ParseErrorKind::UnopenedParen, i))?;
                 if natom == 0 {
-                    return None;
+                    // `(a|)` is fine, but `()` only is when lenient
+                    if nalt == 0 && !lenient {
+                        return Err(err(ParseErrorKind::EmptyGroup, i));
+                    }
+                    dst.push(Token::Empty);
+                    natom += 1;
                 }
                 natom -= 1;
                 while natom > 0 {
-                    dst.push(b'.');
+                    dst.push(Token::Concat);
                     natom -= 1;
                 }
                 while nalt > 0 {
-                    dst.push(b'|');
+                    dst.push(Token::Alternate);
                     nalt -= 1;
                 }
+                dst.push(Token::Group(p.group));
                 nalt = p.nalt;
                 natom = p.natom;
                 natom += 1;
             }
             b'*' | b'+' | b'?' => {
                 if natom == 0 {
-                    return None;
+                    return Err(err(ParseErrorKind::MissingOperand, i));
                 }
-                dst.push(byte);
+                dst.push(match byte {
+                    b'*' => Token::ZeroOrMore,
+                    b'+' => Token::OneOrMore,
+                    _ => Token::ZeroOrOne,
+                });
+            }
+            b'{' => {
+                if natom == 0 {
+                    return Err(err(ParseErrorKind::MissingOperand, i));
+                }
+                let (min, max) =
+                    parse_counts(&mut it).map_err(|kind| err(kind, i))?;
+                dst.push(Token::Repeat { min, max });
             }
-            // Not handled in the original program.
-            // Since '.' is a meta character in the
-            // postfix syntax, it can wreak havoc
-            // if we allow it here.
-            b'.' => return None,
             _ => {
                 if natom > 1 {
                     natom -= 1;
-                    dst.push(b'.');
+                    dst.push(Token::Concat);
+                }
+                // The original program can't support '.' since
+                // it uses it as the concatenation operator in
+                // its postfix syntax. Our postfix tokens can't
+                // be confused with one another, so we can.
+                // '^' and '$' are atoms too, even though
+                // they don't consume any bytes.
+                dst.push(match byte {
+                    b'.' if utf8 => {
+                        Token::AnyChar { newline: flags.dot_matches_newline }
+                    }
+                    b'.' if flags.dot_matches_newline => Token::Any,
+                    b'.' => {
+                        let mut set = ByteSet::new();
+                        set.insert(b'\n');
+                        Token::Class { set, negated: true }
+                    }
+                    b'^' if flags.multi_line => Token::StartLine,
+                    b'$' if flags.multi_line => Token::EndLine,
+                    b'^' => Token::StartText,
+                    b'$' => Token::EndText,
+                    b'[' => {
+                        let (set, negated) = parse_class(&mut it)
+                            .map_err(|kind| err(kind, i))?;
+                        Token::Class { set, negated }
+                    }
+                    b'\\' => parse_escaped_atom(&mut it)
+                        .map_err(|kind| err(kind, i))?,
+                    _ => Token::Byte(byte),
+                });
+                // The rest of a codepoint that takes more than one
+                // byte is concatenated onto its first byte. Bytes
+                // that aren't valid UTF-8 are left on their own.
+                if utf8 && byte >= 0x80 {
+                    let end = i + utf8_len(byte);
+                    let valid = re
+                        .get(i..end)
+                        .is_some_and(|c| std::str::from_utf8(c).is_ok());
+                    if valid {
+                        for &byte in &re[i + 1..end] {
+                            it.next();
+                            dst.push(Token::Byte(byte));
+                            dst.push(Token::Concat);
+                        }
+                    }
                 }
-                dst.push(byte);
                 natom += 1;
             }
         }
     }
-    if !paren.is_empty() {
-        return None;
+    if let Some(p) = paren.first() {
+        return Err(err(ParseErrorKind::UnclosedParen, p.offset));
     }
     // The original program doesn't handle this case, which in turn
     // causes UB in post2nfa. It occurs when a pattern ends with a |.
-    // Other cases like `a||b` and `(a|)` are rejected correctly above.
     if natom == 0 && nalt > 0 {
-        return None;
+        dst.push(Token::Empty);
+        natom += 1;
     }
     natom -= 1;
     while natom > 0 {
-        dst.push(b'.');
+        dst.push(Token::Concat);
         natom -= 1;
     }
     while nalt > 0 {
-        dst.push(b'|');
+        dst.push(Token::Alternate);
         nalt -= 1;
     }
-    Some(dst)
-}
-
-// NFA states in a single contiguous
-// allocation. States contain indices
-// into this NFA instead of pointers
-// directly to other states that they
-// transition to.
-struct NFA {
-    start: StateID,
-    states: Vec<State>,
-}
-
-// The type of a state handle. These
-// are meant to be always-valid indices
-// into NFA::states.
-type StateID = u32;
-
-// A state matches a literal byte,
-// or splits execution to two other states,
-// or indicates a match.
-enum State {
-    Literal { byte: u8, out: StateID },
-    Split { out1: StateID, out2: StateID },
-    Match,
-}
-
-// A partial NFA fragment with a start state
-// and a list of instructions to create valid
-// handles to the next state.
-struct Frag {
-    start: StateID,
-    out: Vec<ToPatch>,
+    Ok((dst, ngroups as usize))
 }
 
-// An instruction to patch a state's out/out1
-// or out2 handle to a valid state.
-#[derive(Clone, Copy)]
-enum ToPatch {
-    // patch 'out' or 'out1' in given state
-    Out1(StateID),
-    // patch 'out2' in given state
-    Out2(StateID),
-}
+// The most tokens a postfix pattern may have once its counted
+// repetitions are expanded. Each token becomes at most one
+// state, so this also bounds the size of the NFA.
+const MAX_EXPANDED: usize = 100_000;
 
-impl NFA {
-    // Convert postfix regular expression to NFA.
-    // Return start state.
-    fn post2nfa(postfix: &[u8]) -> Option<NFA> {
-        let mut nfa = NFA { start: 0, states: vec![] };
-        let mut stack: Vec<Frag> = vec![];
-        for &byte in postfix.iter() {
-            match byte {
-                // catenate
-                b'.' => {
-                    let e2 = stack.pop().unwrap();
-                    let e1 = stack.pop().unwrap();
-                    nfa.patch(&e1.out, e2.start);
-                    stack.push(Frag { start: e1.start, out: e2.out });
-                }
-                // alternate
-                b'|' => {
-                    let e2 = stack.pop().unwrap();
-                    let mut e1 = stack.pop().unwrap();
-                    let s = nfa.alloc(State::Split {
-                        out1: e1.start,
-                        out2: e2.start,
-                    });
-                    e1.out.extend(e2.out);
-                    stack.push(Frag { start: s, out: e1.out });
+// Rewrite each counted repetition in a postfix pattern in terms
+// of concatenation, `?` and `*`, by copying its operand. For
+// example, `a{2,4}` becomes `aa(a(a)?)?` and `a{2,}` becomes
+// `aaa*`. Nesting the optional copies, rather than writing
+// `aaa?a?`, means a failure to match one copy skips the rest
+// without visiting them.
+//
+// This returns None if the postfix pattern is malformed or
+// if the expansion has more than MAX_EXPANDED tokens.
+fn expand_repetitions(postfix: &[Token]) -> Option<Vec<Token>> {
+    let mut dst = vec![];
+    // the start offset in dst of each expression on the stack
+    let mut stack: Vec<usize> = vec![];
+    for &token in postfix.iter() {
+        match token {
+            Token::Concat | Token::Alternate => {
+                stack.pop()?;
+                stack.last()?;
+                dst.push(token);
+            }
+            Token::ZeroOrOne
+            | Token::ZeroOrMore
+            | Token::OneOrMore
+            | Token::Group(_) => {
+                stack.last()?;
+                dst.push(token);
+            }
+            Token::Byte(_)
+            | Token::Any
+            | Token::AnyChar { .. }
+            | Token::Class { .. }
+            | Token::StartText
+            | Token::EndText
+            | Token::StartLine
+            | Token::EndLine
+            | Token::WordBoundary { .. }
+            | Token::Empty => {
+                stack.push(dst.len());
+                dst.push(token);
+            }
+            Token::Repeat { min, max } => {
+                let start = *stack.last()?;
+                let operand = dst.split_off(start);
+                let copies = max.unwrap_or(min + 1);
+                if operand.len() * copies as usize > MAX_EXPANDED {
+                    return None;
                 }
-                // zero or one
-                b'?' => {
-                    let mut e = stack.pop().unwrap();
-                    let s = nfa.alloc(State::Split { out1: e.start, out2: 0 });
-                    e.out.push(ToPatch::Out2(s));
-                    stack.push(Frag { start: s, out: e.out });
+                // with no copies at all, only the empty string is left
+                if copies == 0 {
+                    dst.push(Token::Empty);
                 }
-                // zero or more
-                b'*' => {
-                    let e = stack.pop().unwrap();
-                    let s = nfa.alloc(State::Split { out1: e.start, out2: 0 });
-                    nfa.patch(&e.out, s);
-                    let out = vec![ToPatch::Out2(s)];
-                    stack.push(Frag { start: s, out });
+                for i in 0..min {
+                    dst.extend_from_slice(&operand);
+                    if i > 0 {
+                        dst.push(Token::Concat);
+                    }
                 }
-                // one or more
-                b'+' => {
-                    let e = stack.pop().unwrap();
-                    let s = nfa.alloc(State::Split { out1: e.start, out2: 0 });
-                    nfa.patch(&e.out, s);
-                    let out = vec![ToPatch::Out2(s)];
-                    stack.push(Frag { start: e.start, out });
+                if max.is_none() {
+                    dst.extend_from_slice(&operand);
+                    dst.push(Token::ZeroOrMore);
+                } else if copies > min {
+                    for _ in min..copies {
+                        dst.extend_from_slice(&operand);
+                    }
+                    dst.push(Token::ZeroOrOne);
+                    for _ in min + 1..copies {
+                        dst.push(Token::Concat);
+                        dst.push(Token::ZeroOrOne);
+                    }
                 }
-                _ => {
-                    let s = nfa.alloc(State::Literal { byte, out: 0 });
-                    let out = vec![ToPatch::Out1(s)];
-                    stack.push(Frag { start: s, out });
+                // concatenate the required copies with the optional ones
+                if min > 0 && copies > min {
+                    dst.push(Token::Concat);
                 }
             }
         }
-        let e = stack.pop().unwrap();
-        if !stack.is_empty() {
+        if dst.len() > MAX_EXPANDED {
             return None;
         }
-        let s = nfa.alloc(State::Match);
-        nfa.start = e.start;
-        nfa.patch(&e.out, s);
-        Some(nfa)
     }
+    if stack.len() != 1 {
+        return None;
+    }
+    Some(dst)
+}
 
-    // Puts the given state on the heap and returns a stable
-    // identifier for that state.
-    fn alloc(&mut self, state: State) -> StateID {
-        let id = self.states.len();
-        self.states.push(state);
-        // Our parser limits ensure this always succeeds.
-        StateID::try_from(id).expect("less than StateID::MAX states")
+// Work out how many states post2nfa makes for a postfix pattern
+// from re2post, without expanding its counted repetitions or
+// building anything. This is cheap even when the NFA would be
+// huge, so a caller can reject a pattern like `(a{1000}){1000}`
+// before spending any time or memory on it.
+//
+// The count is exact for the pattern as given. dedup_alternates
+// and factor_alternates can only make an NFA smaller, so after
+// them, this is an upper bound. It saturates instead of
+// overflowing, and a malformed pattern counts as empty.
+fn estimate_states(postfix: &[Token]) -> usize {
+    // the size of each expression on the stack
+    let mut stack: Vec<usize> = vec![];
+    for &token in postfix.iter() {
+        let size = match token {
+            Token::Concat | Token::Alternate => {
+                let e2 = stack.pop().unwrap_or(0);
+                let e1 = stack.pop().unwrap_or(0);
+                let split = usize::from(token == Token::Alternate);
+                e1.saturating_add(e2).saturating_add(split)
+            }
+            Token::ZeroOrOne | Token::ZeroOrMore | Token::OneOrMore => {
+                stack.pop().unwrap_or(0).saturating_add(1)
+            }
+            Token::Group(_) => stack.pop().unwrap_or(0).saturating_add(2),
+            // see expand_repetitions for the tokens this becomes
+            Token::Repeat { min, max } => {
+                let e = stack.pop().unwrap_or(0);
+                let copies = max.unwrap_or(min + 1) as usize;
+                let splits = match max {
+                    None => 1,
+                    Some(max) => max.saturating_sub(min) as usize,
+                };
+                let empty = usize::from(copies == 0);
+                e.saturating_mul(copies).saturating_add(splits + empty)
+            }
+            // see NFA::any_char
+            Token::AnyChar { .. } => {
+                let ranges: usize =
+                    UTF8_SEQUENCES.iter().map(|seq| seq.len()).sum();
+                2 + ranges + UTF8_SEQUENCES.len()
+            }
+            Token::Byte(_)
+            | Token::Any
+            | Token::Class { .. }
+            | Token::StartText
+            | Token::EndText
+            | Token::StartLine
+            | Token::EndLine
+            | Token::WordBoundary { .. }
+            | Token::Empty => 1,
+        };
+        stack.push(size);
     }
+    // plus the match state
+    stack.pop().unwrap_or(0).saturating_add(1)
+}
 
-    // Perform all patch instructions such that all
-    // handles point to the state given.
-    fn patch(&mut self, l: &[ToPatch], s: StateID) {
-        for &p in l.iter() {
-            match p {
-                ToPatch::Out1(sid) => match self.states[sid as usize] {
-                    State::Literal { ref mut out, .. } => {
-                        *out = s;
+// Remove the Group markers from a postfix pattern, for searches
+// that don't report captures. Each group gets its own index, so
+// with them left in, `(a)|(a)` has no duplicate alternatives to
+// remove and no common first atom to factor out. Without them,
+// groups also build no Save states.
+fn strip_groups(postfix: &[Token]) -> Vec<Token> {
+    postfix.iter().copied().filter(|t| !matches!(t, Token::Group(_))).collect()
+}
+
+// Remove exact duplicates among the top level alternatives of
+// a postfix pattern, keeping the first of each. For example,
+// `cat|dog|cat` becomes `cat|dog`. An alternation matches if
+// any of its alternatives do, so dropping a repeat of an
+// earlier alternative doesn't change what the pattern matches,
+// but it does make the NFA smaller.
+//
+// Since alternation is associative, nested alternations like
+// `(a|b)|a` are flattened into a single list of alternatives.
+fn dedup_alternates(postfix: &[Token]) -> Vec<Token> {
+    let Some(second) = second_operands(postfix) else {
+        return postfix.to_vec();
+    };
+    let mut alternates: Vec<&[Token]> = vec![];
+    for (start, end) in top_level_alternates(postfix, &second) {
+        if !alternates.contains(&&postfix[start..end]) {
+            alternates.push(&postfix[start..end]);
+        }
+    }
+    alternate(&alternates)
+}
+
+// Factor out a common prefix of atoms from adjacent top level
+// alternatives of a postfix pattern. For example, `abc|abd|abe`
+// becomes `ab(c|d|e)`, and then the same is done to the new
+// alternation, so `abc|abd|ae` becomes `a(b(c|d)|e)`. The shared
+// prefix is then matched by a single chain of states instead of
+// one per alternative.
+//
+// Only adjacent alternatives are grouped together, so the
+// order in which alternatives are tried is unchanged. Each
+// alternative keeps at least one factor of its own, since
+// factoring out all of it would leave an empty alternative in
+// its place, which needs a state of its own.
+//
+// Each alternative is split into the factors it concatenates
+// once, up front. After that, a factored alternation is just a
+// run of alternatives and how many factors of each have been
+// factored out, so nothing is copied until the result is
+// written. A stack of our own takes the place of recursion.
+fn factor_alternates(postfix: &[Token]) -> Vec<Token> {
+    // what has yet to be written, in reverse order
+    enum Todo {
+        // the alternation of the given alternatives, without
+        // their first `depth` factors
+        Alternation { alts: Range<usize>, depth: usize },
+        // the factors of an alternative from the given one on
+        Suffix { alt: usize, depth: usize },
+        // the given factors of an alternative
+        Prefix { alt: usize, factors: Range<usize> },
+        Token(Token),
+    }
+
+    let Some(second) = second_operands(postfix) else {
+        return postfix.to_vec();
+    };
+    let factors: Vec<Vec<Range<usize>>> =
+        top_level_alternates(postfix, &second)
+            .into_iter()
+            .map(|(start, end)| concat_factors(postfix, &second, start, end))
+            .collect();
+    // the atom that the given factor of an alternative is, if any
+    let atom = |alt: usize, depth: usize| {
+        let factor = factors[alt].get(depth)?;
+        let token = postfix[factor.start];
+        let is_atom = matches!(
+            token,
+            Token::Byte(_)
+                | Token::Any
+                | Token::AnyChar { .. }
+                | Token::Class { .. }
+                | Token::StartText
+                | Token::EndText
+                | Token::StartLine
+                | Token::EndLine
+                | Token::WordBoundary { .. }
+        );
+        (factor.len() == 1 && is_atom).then_some(token)
+    };
+    // write the given factors as a chain of Concats
+    let concat = |dst: &mut Vec<Token>, factors: &[Range<usize>]| {
+        for (i, factor) in factors.iter().enumerate() {
+            dst.extend_from_slice(&postfix[factor.clone()]);
+            if i > 0 {
+                dst.push(Token::Concat);
+            }
+        }
+    };
+
+    let mut dst = Vec::with_capacity(postfix.len());
+    let mut todo =
+        vec![Todo::Alternation { alts: 0..factors.len(), depth: 0 }];
+    while let Some(next) = todo.pop() {
+        let (alts, depth) = match next {
+            Todo::Alternation { alts, depth } => (alts, depth),
+            Todo::Suffix { alt, depth } => {
+                concat(&mut dst, &factors[alt][depth..]);
+                continue;
+            }
+            Todo::Prefix { alt, factors: range } => {
+                concat(&mut dst, &factors[alt][range]);
+                continue;
+            }
+            Todo::Token(token) => {
+                dst.push(token);
+                continue;
+            }
+        };
+        // an alternative can only share a prefix if it has at
+        // least one factor left over after it
+        let shares = |alt: usize, depth: usize| {
+            factors[alt].len() > depth + 1 && atom(alt, depth).is_some()
+        };
+        let mut items = vec![];
+        let mut i = alts.start;
+        while i < alts.end {
+            // find the run of alternatives starting with the same atom
+            let mut j = i + 1;
+            if shares(i, depth) {
+                while j < alts.end
+                    && shares(j, depth)
+                    && atom(j, depth) == atom(i, depth)
+                {
+                    j += 1;
+                }
+            }
+            if j - i == 1 {
+                items.push(Todo::Suffix { alt: i, depth });
+                i = j;
+                continue;
+            }
+            // the longest prefix of atoms the whole run shares
+            let mut len = 1;
+            while (i..j).all(|alt| {
+                shares(alt, depth + len)
+                    && atom(alt, depth + len) == atom(i, depth + len)
+            }) {
+                len += 1;
+            }
+            // the prefix, then the alternation of what's left,
+            // then a Concat of the two
+            items.push(Todo::Prefix { alt: i, factors: depth..depth + len });
+            items.push(Todo::Alternation { alts: i..j, depth: depth + len });
+            items.push(Todo::Token(Token::Concat));
+            i = j;
+        }
+        // Suffix and Alternation items each stand for one
+        // alternative, so there's one fewer Alternate than them.
+        let count = items
+            .iter()
+            .filter(|item| {
+                matches!(item, Todo::Suffix { .. } | Todo::Alternation { .. })
+            })
+            .count();
+        for _ in 1..count {
+            todo.push(Todo::Token(Token::Alternate));
+        }
+        todo.extend(items.into_iter().rev());
+    }
+    dst
+}
+
+// For each binary operator (Concat or Alternate) in a postfix pattern,
+// the offset at which its second operand starts. Its first
+// operand starts wherever the operator's expression itself
+// does. Entries for every other byte are meaningless.
+//
+// This returns None if the postfix pattern is malformed.
+fn second_operands(postfix: &[Token]) -> Option<Vec<usize>> {
+    let mut second = vec![0; postfix.len()];
+    // the start offset of each expression on the stack
+    let mut stack: Vec<usize> = vec![];
+    for (i, &byte) in postfix.iter().enumerate() {
+        match byte {
+            Token::Concat | Token::Alternate => {
+                let start2 = stack.pop()?;
+                let start1 = stack.pop()?;
+                second[i] = start2;
+                stack.push(start1);
+            }
+            Token::ZeroOrOne
+            | Token::ZeroOrMore
+            | Token::OneOrMore
+            | Token::Group(_)
+            | Token::Repeat { .. } => {
+                stack.last()?;
+            }
+            Token::Byte(_)
+            | Token::Any
+            | Token::AnyChar { .. }
+            | Token::Class { .. }
+            | Token::StartText
+            | Token::EndText
+            | Token::StartLine
+            | Token::EndLine
+            | Token::WordBoundary { .. }
+            | Token::Empty => stack.push(i),
+        }
+    }
+    if stack.len() != 1 {
+        return None;
+    }
+    Some(second)
+}
+
+// Return the start and end offsets of each alternative in the
+// tree of Alternate operators at the root of a postfix pattern, in
+// order.
+fn top_level_alternates(
+    postfix: &[Token],
+    second: &[usize],
+) -> Vec<(usize, usize)> {
+    let mut alternates = vec![];
+    let mut todo = vec![(0, postfix.len())];
+    while let Some((start, end)) = todo.pop() {
+        let last = end - 1;
+        if postfix[last] == Token::Alternate {
+            // push the second operand first so
+            // that the first one is visited first
+            todo.push((second[last], last));
+            todo.push((start, second[last]));
+        } else {
+            alternates.push((start, end));
+        }
+    }
+    alternates
+}
+
+// Return the ranges of the factors that the postfix expression
+// at postfix[start..end] concatenates, in order. An expression
+// that isn't a concatenation is a single factor.
+fn concat_factors(
+    postfix: &[Token],
+    second: &[usize],
+    start: usize,
+    end: usize,
+) -> Vec<Range<usize>> {
+    // Concatenations are left leaning, so `abc` is `ab.c.` (writing
+    // '.' for Concat). Following first operands from the root walks
+    // the factors from last to first.
+    let mut factors = vec![];
+    let mut end = end;
+    while postfix[end - 1] == Token::Concat {
+        factors.push(second[end - 1]..end - 1);
+        end = second[end - 1];
+    }
+    factors.push(start..end);
+    factors.reverse();
+    factors
+}
+
+// Return the postfix for the alternation of the given
+// postfix expressions, which must not be empty.
+fn alternate(alternates: &[&[Token]]) -> Vec<Token> {
+    let mut dst = alternates.concat();
+    dst.resize(dst.len() + alternates.len() - 1, Token::Alternate);
+    dst
+}
+
+// Find every problem in the pattern that would cause
+// re2post to reject it, instead of just the first one.
+//
+// This follows the same bookkeeping as re2post, but when
+// it sees a problem, it records it and carries on as if
+// the pattern were valid at that point. For example, an
+// empty group is treated as if it had an atom and
+// a stray ')' is ignored. This means a single mistake
+// won't cascade into a bunch of spurious follow on
+// problems. An empty result means re2post accepts the
+// pattern, when given the same lenient flag.
+fn diagnose(re: &[u8], limits: &Limits, lenient: bool) -> Vec<ParseError> {
+    let mut diags = vec![];
+    let mut report = |offset, kind| {
+        diags.push(ParseError { kind, offset });
+    };
+    if re.len() > limits.max_pattern_len {
+        report(limits.max_pattern_len, ParseErrorKind::TooLong);
+    }
+    let (mut nalt, mut natom) = (0, 0);
+    // the offset of each open paren, along with
+    // its saved nalt and natom
+    let mut paren: Vec<(usize, i32, i32)> = vec![];
+    let mut it = re.iter();
+    while let Some(&byte) = it.next() {
+        let i = re.len() - it.len() - 1;
+        match byte {
+            b'(' => {
+                if paren.len() == limits.max_paren_depth {
+                    report(i, ParseErrorKind::TooDeep);
+                }
+                paren.push((i, nalt, natom.min(1)));
+                nalt = 0;
+                natom = 0;
+            }
+            b'|' => {
+                natom = 0;
+                nalt += 1;
+            }
+            b')' => {
+                let Some((_, pnalt, pnatom)) = paren.pop() else {
+                    report(i, ParseErrorKind::UnopenedParen);
+                    continue;
+                };
+                if natom == 0 && nalt == 0 && !lenient {
+                    report(i, ParseErrorKind::EmptyGroup);
+                }
+                nalt = pnalt;
+                natom = pnatom + 1;
+            }
+            b'*' | b'+' | b'?' => {
+                if natom == 0 {
+                    report(i, ParseErrorKind::MissingOperand);
+                }
+            }
+            b'{' => {
+                if natom == 0 {
+                    report(i, ParseErrorKind::MissingOperand);
+                }
+                if let Err(kind) = parse_counts(&mut it) {
+                    report(i, kind);
+                }
+            }
+            b'[' => {
+                if let Err(kind) = parse_class(&mut it) {
+                    report(i, kind);
+                }
+                natom += 1;
+            }
+            b'\\' => {
+                if let Err(kind) = parse_escaped_atom(&mut it) {
+                    report(i, kind);
+                }
+                natom += 1;
+            }
+            _ => natom += 1,
+        }
+    }
+    for &(offset, _, _) in paren.iter() {
+        report(offset, ParseErrorKind::UnclosedParen);
+    }
+    diags
+}
+
+// The ways in which building an NFA can fail.
+enum BuildError {
+    // the postfix pattern doesn't describe a single expression,
+    // or it still has counted repetitions in it
+    MalformedPostfix,
+    // there are more states than a StateID can identify
+    TooManyStates,
+    // expanding the counted repetitions made the pattern
+    // longer than MAX_EXPANDED tokens
+    RepetitionsTooBig,
+}
+
+impl std::fmt::Display for BuildError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            BuildError::MalformedPostfix => write!(f, "malformed postfix"),
+            BuildError::TooManyStates => write!(f, "too many states"),
+            BuildError::RepetitionsTooBig => {
+                write!(f, "counted repetitions make the pattern too big")
+            }
+        }
+    }
+}
+
+// A pattern that has been parsed, but not built into an NFA yet,
+// so that it can be built more than once under different options
+// without being parsed again each time.
+struct ParsedPattern {
+    postfix: Vec<Token>,
+    // the number of groups, not counting group 0
+    ngroups: usize,
+    // whether the pattern starts with `(?i)`, which folds
+    // case no matter what the options say
+    ignore_case: bool,
+}
+
+// The options a ParsedPattern is built under. Unlike the ones in
+// Flags, these don't change how a pattern is parsed.
+#[derive(Clone, Copy)]
+struct BuildOptions {
+    // keep the groups, so the NFA can report captures
+    captures: bool,
+    // letters match either case
+    ignore_case: bool,
+}
+
+impl ParsedPattern {
+    // The postfix pattern to build from under the given options,
+    // and the number of groups in it. Without captures, groups
+    // are only in the way (see strip_groups).
+    fn postfix(&self, options: BuildOptions) -> (Vec<Token>, usize) {
+        if options.captures {
+            (self.postfix.clone(), self.ngroups)
+        } else {
+            (strip_groups(&self.postfix), 0)
+        }
+    }
+
+    // Build an NFA from this pattern under the given options.
+    fn compile(
+        &self,
+        options: BuildOptions,
+        limits: &Limits,
+    ) -> Result<NFA, BuildError> {
+        let (post, ngroups) = self.postfix(options);
+        let post =
+            expand_repetitions(&post).ok_or(BuildError::RepetitionsTooBig)?;
+        let post = factor_alternates(&dedup_alternates(&post));
+        let nfa = NFA::post2nfa(&post, ngroups, limits)?;
+        if options.ignore_case || self.ignore_case {
+            Ok(nfa.ignore_case())
+        } else {
+            Ok(nfa)
+        }
+    }
+}
+
+// NFA states in a single contiguous
+// allocation. States contain indices
+// into this NFA instead of pointers
+// directly to other states that they
+// transition to.
+#[allow(clippy::upper_case_acronyms)]
+struct NFA {
+    start: StateID,
+    states: Vec<State>,
+    // see NFA::min_remaining
+    min_remaining: OnceLock<Box<[u32]>>,
+    // see Limits::max_states
+    max_states: usize,
+    // the number of capture groups, including group 0
+    captures_len: usize,
+}
+
+// The type of a state handle. These
+// are meant to be always-valid indices
+// into NFA::states.
+type StateID = u32;
+
+// A state matches a literal byte,
+// or matches any byte,
+// or matches any byte in a set (or not in it, if negated),
+// or matches any byte in a range,
+// or passes through only at the start of the haystack,
+// or passes through only at the end of the haystack,
+// or passes through only at a word boundary (or only not at one),
+// or passes through while recording the offset in a capture slot,
+// or splits execution to two other states,
+// or indicates a match.
+enum State {
+    Literal { byte: u8, out: StateID },
+    Any { out: StateID },
+    Class { set: Box<ByteSet>, negated: bool, out: StateID },
+    Range { lo: u8, hi: u8, out: StateID },
+    StartText { out: StateID },
+    EndText { out: StateID },
+    StartLine { out: StateID },
+    EndLine { out: StateID },
+    WordBoundary { negate: bool, out: StateID },
+    Save { slot: usize, out: StateID },
+    Split { out1: StateID, out2: StateID },
+    Match,
+}
+
+impl State {
+    // A state that matches the bytes in the set, or the bytes not
+    // in it if negated. When that's a single range of bytes, like
+    // `[a-z]`, it's a Range, which takes less room and is quicker
+    // to test than a Class, or a Literal if it's a single byte.
+    // Negated classes are always a Class, since ignore_case treats
+    // them differently.
+    fn class(set: ByteSet, negated: bool, out: StateID) -> State {
+        match *set.ranges() {
+            [(lo, hi)] if !negated && lo == hi => {
+                State::Literal { byte: lo, out }
+            }
+            [(lo, hi)] if !negated => State::Range { lo, hi, out },
+            _ => State::Class { set: Box::new(set), negated, out },
+        }
+    }
+
+    // The bytes a thread here can read, and the state it moves to
+    // after reading one, or None if this state doesn't read bytes.
+    fn transition(&self) -> Option<(ByteSet, StateID)> {
+        let mut bytes = ByteSet::new();
+        match *self {
+            State::Literal { byte, out } => {
+                bytes.insert(byte);
+                Some((bytes, out))
+            }
+            State::Any { out } => Some((bytes.complement(), out)),
+            State::Class { ref set, negated, out } => {
+                Some((if negated { set.complement() } else { **set }, out))
+            }
+            State::Range { lo, hi, out } => {
+                bytes.insert_range(lo, hi);
+                Some((bytes, out))
+            }
+            _ => None,
+        }
+    }
+
+    // the state a thread here moves to after reading
+    // the given byte, or None if it doesn't match it
+    fn next(&self, byte: u8) -> Option<StateID> {
+        match *self {
+            State::Literal { byte: b, out } if b == byte => Some(out),
+            State::Any { out } => Some(out),
+            State::Class { ref set, negated, out }
+                if set.contains(byte) != negated =>
+            {
+                Some(out)
+            }
+            State::Range { lo, hi, out } if lo <= byte && byte <= hi => {
+                Some(out)
+            }
+            _ => None,
+        }
+    }
+}
+
+// A partial NFA fragment with a start state
+// and a list of instructions to create valid
+// handles to the next state.
+struct Frag {
+    start: StateID,
+    out: Vec<ToPatch>,
+}
+
+// An instruction to patch a state's out/out1
+// or out2 handle to a valid state.
+#[derive(Clone, Copy)]
+enum ToPatch {
+    // patch 'out' or 'out1' in given state
+    Out1(StateID),
+    // patch 'out2' in given state
+    Out2(StateID),
+}
+
+// Pop the top fragment off the stack, or
+// fail if the postfix pattern is malformed.
+fn pop(stack: &mut Vec<Frag>) -> Result<Frag, BuildError> {
+    stack.pop().ok_or(BuildError::MalformedPostfix)
+}
+
+// The multibyte sequences NFA::any_char matches. Each row is the
+// ranges of bytes allowed in one kind of sequence, except for its
+// last byte, which is always a continuation byte in 80-BF.
+const UTF8_SEQUENCES: &[&[(u8, u8)]] = &[
+    &[(0xC2, 0xDF)],
+    &[(0xE0, 0xE0), (0xA0, 0xBF)],
+    &[(0xE1, 0xEC), (0x80, 0xBF)],
+    &[(0xED, 0xED), (0x80, 0x9F)],
+    &[(0xEE, 0xEF), (0x80, 0xBF)],
+    &[(0xF0, 0xF0), (0x90, 0xBF), (0x80, 0xBF)],
+    &[(0xF1, 0xF3), (0x80, 0xBF), (0x80, 0xBF)],
+    &[(0xF4, 0xF4), (0x80, 0x8F), (0x80, 0xBF)],
+];
+
+impl NFA {
+    // create an NFA with no states yet
+    fn new(limits: &Limits) -> NFA {
+        NFA {
+            start: 0,
+            states: vec![],
+            min_remaining: OnceLock::new(),
+            max_states: limits.max_states,
+            captures_len: 1,
+        }
+    }
+
+    // Convert postfix regular expression to NFA.
+    // Return start state.
+    //
+    // re2post only produces well formed postfix, but nothing
+    // here relies on that. Malformed postfix is reported as an
+    // error instead of causing a panic.
+    //
+    // ngroups is the number of groups re2post found. A group can
+    // be gone from postfix by now, e.g., after `(a){0}` is
+    // expanded, but it still counts, and never matches anything.
+    fn post2nfa(
+        postfix: &[Token],
+        ngroups: usize,
+        limits: &Limits,
+    ) -> Result<NFA, BuildError> {
+        let mut nfa = NFA::new(limits);
+        nfa.captures_len = ngroups + 1;
+        let mut stack: Vec<Frag> = vec![];
+        for &token in postfix.iter() {
+            match token {
+                // catenate
+                Token::Concat => {
+                    let e2 = pop(&mut stack)?;
+                    let e1 = pop(&mut stack)?;
+                    nfa.patch(&e1.out, e2.start);
+                    stack.push(Frag { start: e1.start, out: e2.out });
+                }
+                // alternate
+                Token::Alternate => {
+                    let e2 = pop(&mut stack)?;
+                    let mut e1 = pop(&mut stack)?;
+                    let s = nfa.alloc(State::Split {
+                        out1: e1.start,
+                        out2: e2.start,
+                    })?;
+                    e1.out.extend(e2.out);
+                    stack.push(Frag { start: s, out: e1.out });
+                }
+                // zero or one
+                Token::ZeroOrOne => {
+                    let mut e = pop(&mut stack)?;
+                    let s =
+                        nfa.alloc(State::Split { out1: e.start, out2: 0 })?;
+                    e.out.push(ToPatch::Out2(s));
+                    stack.push(Frag { start: s, out: e.out });
+                }
+                // zero or more
+                Token::ZeroOrMore => {
+                    let e = pop(&mut stack)?;
+                    let s =
+                        nfa.alloc(State::Split { out1: e.start, out2: 0 })?;
+                    nfa.patch(&e.out, s);
+                    let out = vec![ToPatch::Out2(s)];
+                    stack.push(Frag { start: s, out });
+                }
+                // one or more
+                Token::OneOrMore => {
+                    let e = pop(&mut stack)?;
+                    let s =
+                        nfa.alloc(State::Split { out1: e.start, out2: 0 })?;
+                    nfa.patch(&e.out, s);
+                    let out = vec![ToPatch::Out2(s)];
+                    stack.push(Frag { start: e.start, out });
+                }
+                // save where the group starts and ends
+                Token::Group(index) => {
+                    let e = pop(&mut stack)?;
+                    let slot = 2 * index as usize;
+                    let end =
+                        nfa.alloc(State::Save { slot: slot + 1, out: 0 })?;
+                    nfa.patch(&e.out, end);
+                    let s = nfa.alloc(State::Save { slot, out: e.start })?;
+                    let out = vec![ToPatch::Out1(end)];
+                    stack.push(Frag { start: s, out });
+                }
+                Token::Repeat { .. } => {
+                    return Err(BuildError::MalformedPostfix);
+                }
+                Token::Byte(byte) => {
+                    let s = nfa.alloc(State::Literal { byte, out: 0 })?;
+                    let out = vec![ToPatch::Out1(s)];
+                    stack.push(Frag { start: s, out });
+                }
+                Token::Any => {
+                    let s = nfa.alloc(State::Any { out: 0 })?;
+                    let out = vec![ToPatch::Out1(s)];
+                    stack.push(Frag { start: s, out });
+                }
+                Token::AnyChar { newline } => {
+                    stack.push(nfa.any_char(newline)?)
+                }
+                // There's no state that matches the empty string on
+                // its own, but a split with both arrows pointing at
+                // the same place does the job.
+                Token::Empty => {
+                    let s = nfa.alloc(State::Split { out1: 0, out2: 0 })?;
+                    let out = vec![ToPatch::Out1(s), ToPatch::Out2(s)];
+                    stack.push(Frag { start: s, out });
+                }
+                Token::StartText => {
+                    let s = nfa.alloc(State::StartText { out: 0 })?;
+                    let out = vec![ToPatch::Out1(s)];
+                    stack.push(Frag { start: s, out });
+                }
+                Token::EndText => {
+                    let s = nfa.alloc(State::EndText { out: 0 })?;
+                    let out = vec![ToPatch::Out1(s)];
+                    stack.push(Frag { start: s, out });
+                }
+                Token::StartLine => {
+                    let s = nfa.alloc(State::StartLine { out: 0 })?;
+                    let out = vec![ToPatch::Out1(s)];
+                    stack.push(Frag { start: s, out });
+                }
+                Token::EndLine => {
+                    let s = nfa.alloc(State::EndLine { out: 0 })?;
+                    let out = vec![ToPatch::Out1(s)];
+                    stack.push(Frag { start: s, out });
+                }
+                Token::WordBoundary { negate } => {
+                    let s =
+                        nfa.alloc(State::WordBoundary { negate, out: 0 })?;
+                    let out = vec![ToPatch::Out1(s)];
+                    stack.push(Frag { start: s, out });
+                }
+                Token::Class { set, negated } => {
+                    let s = nfa.alloc(State::class(set, negated, 0))?;
+                    let out = vec![ToPatch::Out1(s)];
+                    stack.push(Frag { start: s, out });
+                }
+            }
+        }
+        let e = pop(&mut stack)?;
+        if !stack.is_empty() {
+            return Err(BuildError::MalformedPostfix);
+        }
+        let s = nfa.alloc(State::Match)?;
+        nfa.start = e.start;
+        nfa.patch(&e.out, s);
+        Ok(nfa)
+    }
+
+    // Build a fragment that matches any one UTF-8 encoded codepoint,
+    // or any but '\n' if newline is false. This leaves out overlong
+    // encodings, surrogates and anything above U+10FFFF, none of
+    // which are valid UTF-8.
+    fn any_char(&mut self, newline: bool) -> Result<Frag, BuildError> {
+        let class = |lo, hi, out| State::Range { lo, hi, out };
+        // the shared last byte of every multibyte sequence
+        let last = self.alloc(class(0x80, 0xBF, 0))?;
+        let mut ascii = ByteSet::new();
+        ascii.insert_range(0x00, 0x7F);
+        if !newline {
+            ascii.remove(b'\n');
+        }
+        let ascii = self.alloc(State::class(ascii, false, 0))?;
+        let mut starts = vec![ascii];
+        for ranges in UTF8_SEQUENCES {
+            let mut next = last;
+            for &(start, end) in ranges.iter().rev() {
+                next = self.alloc(class(start, end, next))?;
+            }
+            starts.push(next);
+        }
+        // alternate between all of the sequences
+        let mut start = starts[starts.len() - 1];
+        for &s in starts[..starts.len() - 1].iter().rev() {
+            start = self.alloc(State::Split { out1: s, out2: start })?;
+        }
+        let out = vec![ToPatch::Out1(ascii), ToPatch::Out1(last)];
+        Ok(Frag { start, out })
+    }
+
+    // Build an NFA that matches any one of the given literals.
+    // Every byte is matched literally, so there is no syntax
+    // and nothing needs to be escaped. An empty literal
+    // matches the empty string, while no literals at all
+    // match nothing.
+    fn from_literals(
+        literals: &[&[u8]],
+        limits: &Limits,
+    ) -> Result<NFA, BuildError> {
+        let mut nfa = NFA::new(limits);
+        let m = nfa.alloc(State::Match)?;
+        // Each literal is built back to front, so that the
+        // state it transitions to always exists already and
+        // nothing needs to be patched.
+        // Like a trie would, we only build each distinct
+        // literal once. Repeats can never change whether
+        // the NFA matches.
+        let mut distinct: Vec<&[u8]> = vec![];
+        for &literal in literals.iter() {
+            if !distinct.contains(&literal) {
+                distinct.push(literal);
+            }
+        }
+        let starts = distinct
+            .iter()
+            .map(|literal| {
+                let mut s = m;
+                for &byte in literal.iter().rev() {
+                    s = nfa.alloc(State::Literal { byte, out: s })?;
+                }
+                Ok(s)
+            })
+            .collect::<Result<Vec<StateID>, BuildError>>()?;
+        // Then the alternation leads to each literal in
+        // order, unless there's just the one.
+        nfa.start = match *starts {
+            [start] => start,
+            _ => {
+                let start = nfa.alloc(State::Match)?;
+                nfa.states[start as usize] = nfa.fan(start, &starts)?;
+                start
+            }
+        };
+        Ok(nfa)
+    }
+
+    // Build an NFA that matches the reverse of every string this
+    // one matches, e.g., `cba` for `abc`, which can be used to
+    // search a haystack back to front.
+    //
+    // That's done by flipping every arrow. A state's label (the
+    // byte it matches, or the anchor it checks) applies to the
+    // arrow out of it, so flipping an arrow from s to t gives an
+    // arrow from t to a copy of s that leads back to s. Each old
+    // state t then becomes a fan of splits over the flipped
+    // arrows into it. The reversed NFA ends where the old one
+    // started, so the old start's fan also leads to a new match
+    // state, and it starts with a fan over the old match states.
+    // Reading back to front, `^` and `$` trade places.
+    fn reverse(&self) -> Result<NFA, BuildError> {
+        let mut rev = NFA {
+            start: 0,
+            states: vec![],
+            min_remaining: OnceLock::new(),
+            max_states: self.max_states,
+            captures_len: self.captures_len,
+        };
+        // Old state t is at handle t in the reversed NFA too. It's
+        // a placeholder until every arrow into t has been seen.
+        for _ in 0..self.states.len() {
+            rev.alloc(State::Match)?;
+        }
+        let mut incoming: Vec<Vec<StateID>> = vec![vec![]; self.states.len()];
+        for (sid, state) in self.states.iter().enumerate() {
+            let sid = sid as StateID;
+            let (flipped, out) = match *state {
+                State::Literal { byte, out } => {
+                    (State::Literal { byte, out: sid }, out)
+                }
+                State::Any { out } => (State::Any { out: sid }, out),
+                State::Class { ref set, negated, out } => {
+                    let set = set.clone();
+                    (State::Class { set, negated, out: sid }, out)
+                }
+                State::Range { lo, hi, out } => {
+                    (State::Range { lo, hi, out: sid }, out)
+                }
+                State::StartText { out } => (State::EndText { out: sid }, out),
+                State::EndText { out } => (State::StartText { out: sid }, out),
+                State::StartLine { out } => (State::EndLine { out: sid }, out),
+                State::EndLine { out } => (State::StartLine { out: sid }, out),
+                State::WordBoundary { negate, out } => {
+                    (State::WordBoundary { negate, out: sid }, out)
+                }
+                // Unlabeled arrows don't need a copy, and captures
+                // mean nothing in reverse, so a save is unlabeled.
+                State::Save { out, .. } => {
+                    incoming[out as usize].push(sid);
+                    continue;
+                }
+                State::Split { out1, out2 } => {
+                    incoming[out1 as usize].push(sid);
+                    incoming[out2 as usize].push(sid);
+                    continue;
+                }
+                State::Match => continue,
+            };
+            incoming[out as usize].push(rev.alloc(flipped)?);
+        }
+        let m = rev.alloc(State::Match)?;
+        incoming[self.start as usize].push(m);
+        for (sid, targets) in incoming.iter().enumerate() {
+            rev.states[sid] = rev.fan(sid as StateID, targets)?;
+        }
+        let matches: Vec<StateID> = (0..self.states.len() as StateID)
+            .filter(|&sid| matches!(self.states[sid as usize], State::Match))
+            .collect();
+        rev.start = rev.alloc(State::Match)?;
+        rev.states[rev.start as usize] = rev.fan(rev.start, &matches)?;
+        Ok(rev)
+    }
+
+    // Build an NFA that matches exactly the strings that both this
+    // NFA and the other one match, e.g., only `abc` for `a.c` and
+    // `abc`, and nothing at all for `a+` and `b+`.
+    //
+    // This is the product construction: each state of the new NFA
+    // is a pair of states, one from each NFA, standing for a
+    // thread of each that have read the same bytes. A pair where
+    // either state is unlabeled, or an anchor, follows that arrow
+    // on its own, since it doesn't read anything. Otherwise, the
+    // pair reads the bytes that both of its states read, and moves
+    // to the pair of where they go. A pair of match states
+    // matches, and any other pair never does.
+    //
+    // Only pairs that can be reached from the pair of start states
+    // get a state, but that can still be as many as the product of
+    // the sizes of both NFAs. This fails once the new NFA has more
+    // states than this one is allowed.
+    //
+    // Capture groups don't carry over, so the new NFA has none.
+    fn intersect(&self, other: &NFA) -> Result<NFA, BuildError> {
+        // Return the state for the given pair, making a
+        // placeholder for it if there's no state for it yet.
+        fn state_for(
+            product: &mut NFA,
+            ids: &mut HashMap<(StateID, StateID), StateID>,
+            todo: &mut Vec<(StateID, StateID)>,
+            a: StateID,
+            b: StateID,
+        ) -> Result<StateID, BuildError> {
+            if let Some(&sid) = ids.get(&(a, b)) {
+                return Ok(sid);
+            }
+            let sid = product.alloc(State::Match)?;
+            ids.insert((a, b), sid);
+            todo.push((a, b));
+            Ok(sid)
+        }
+
+        let mut product = NFA {
+            start: 0,
+            states: vec![],
+            min_remaining: OnceLock::new(),
+            max_states: self.max_states,
+            captures_len: 1,
+        };
+        let mut ids = HashMap::new();
+        let mut todo = vec![];
+        product.start = state_for(
+            &mut product,
+            &mut ids,
+            &mut todo,
+            self.start,
+            other.start,
+        )?;
+        while let Some((a, b)) = todo.pop() {
+            let sid = ids[&(a, b)];
+            let mut pair =
+                |a, b| state_for(&mut product, &mut ids, &mut todo, a, b);
+            let state =
+                match (&self.states[a as usize], &other.states[b as usize]) {
+                    (&State::Split { out1, out2 }, _) => State::Split {
+                        out1: pair(out1, b)?,
+                        out2: pair(out2, b)?,
+                    },
+                    (_, &State::Split { out1, out2 }) => State::Split {
+                        out1: pair(a, out1)?,
+                        out2: pair(a, out2)?,
+                    },
+                    (&State::Save { out, .. }, _) => {
+                        let out = pair(out, b)?;
+                        State::Split { out1: out, out2: out }
+                    }
+                    (_, &State::Save { out, .. }) => {
+                        let out = pair(a, out)?;
+                        State::Split { out1: out, out2: out }
+                    }
+                    (&State::StartText { out }, _) => {
+                        State::StartText { out: pair(out, b)? }
+                    }
+                    (_, &State::StartText { out }) => {
+                        State::StartText { out: pair(a, out)? }
+                    }
+                    (&State::EndText { out }, _) => {
+                        State::EndText { out: pair(out, b)? }
+                    }
+                    (_, &State::EndText { out }) => {
+                        State::EndText { out: pair(a, out)? }
+                    }
+                    (&State::StartLine { out }, _) => {
+                        State::StartLine { out: pair(out, b)? }
+                    }
+                    (_, &State::StartLine { out }) => {
+                        State::StartLine { out: pair(a, out)? }
+                    }
+                    (&State::EndLine { out }, _) => {
+                        State::EndLine { out: pair(out, b)? }
+                    }
+                    (_, &State::EndLine { out }) => {
+                        State::EndLine { out: pair(a, out)? }
+                    }
+                    (&State::WordBoundary { negate, out }, _) => {
+                        State::WordBoundary { negate, out: pair(out, b)? }
+                    }
+                    (_, &State::WordBoundary { negate, out }) => {
+                        State::WordBoundary { negate, out: pair(a, out)? }
+                    }
+                    (State::Match, State::Match) => State::Match,
+                    (sa, sb) => match (sa.transition(), sb.transition()) {
+                        (Some((mut bytes, out1)), Some((other, out2))) => {
+                            bytes.intersect(&other);
+                            // no need for a pair that can't be reached
+                            if bytes == ByteSet::new() {
+                                NFA::dead(sid)
+                            } else {
+                                State::class(bytes, false, pair(out1, out2)?)
+                            }
+                        }
+                        // a match state and one that reads a byte
+                        _ => NFA::dead(sid),
+                    },
+                };
+            product.states[sid as usize] = state;
+        }
+        Ok(product)
+    }
+
+    // Return true if no string matches both this NFA and the other
+    // one, which is when the start of their intersection can't reach
+    // a match. Anchors count as always holding here, so patterns that
+    // only share strings where an anchor can't hold, like `a^b` and
+    // `ab`, aren't found to be disjoint.
+    fn is_disjoint(&self, other: &NFA) -> Result<bool, BuildError> {
+        let product = self.intersect(other)?;
+        Ok(product.min_remaining()[product.start as usize] == u32::MAX)
+    }
+
+    // Return a state to put at the given handle that leads to each
+    // of the given states without consuming anything, allocating the
+    // splits it needs beyond the first. With nowhere to lead, it's a
+    // dead state.
+    fn fan(
+        &mut self,
+        sid: StateID,
+        targets: &[StateID],
+    ) -> Result<State, BuildError> {
+        let Some((&first, rest)) = targets.split_first() else {
+            return Ok(NFA::dead(sid));
+        };
+        let Some((&last, rest)) = rest.split_last() else {
+            return Ok(State::Split { out1: first, out2: first });
+        };
+        let mut out2 = last;
+        for &sid in rest.iter().rev() {
+            out2 = self.alloc(State::Split { out1: sid, out2 })?;
+        }
+        Ok(State::Split { out1: first, out2 })
+    }
+
+    // Return a state to put at the given handle that never matches.
+    // It reads no byte at all, and leads back to itself so that it
+    // can't look like it reaches any other state.
+    fn dead(sid: StateID) -> State {
+        State::class(ByteSet::new(), false, sid)
+    }
+
+    // Turn this NFA into one that ignores ASCII case, by making
+    // every state that matches a letter match both cases of it.
+    // A literal letter becomes a class of two bytes.
+    //
+    // A class keeps its listed members apart from whether it's
+    // negated, so folding the members does the right thing for
+    // both: `[^x]` ends up matching neither case of x.
+    fn ignore_case(mut self) -> NFA {
+        let fold = |set: &ByteSet| {
+            let mut folded = *set;
+            for byte in set.iter().filter(u8::is_ascii_alphabetic) {
+                folded.insert(byte ^ 0x20);
+            }
+            folded
+        };
+        for state in self.states.iter_mut() {
+            match *state {
+                State::Literal { byte, out } if byte.is_ascii_alphabetic() => {
+                    let mut set = ByteSet::new();
+                    set.insert(byte);
+                    let set = Box::new(fold(&set));
+                    *state = State::Class { set, negated: false, out };
+                }
+                State::Class { ref mut set, .. } => **set = fold(set),
+                // `[a-z]` folds into two ranges, so this
+                // usually turns into a Class
+                State::Range { lo, hi, out } => {
+                    let mut set = ByteSet::new();
+                    set.insert_range(lo, hi);
+                    *state = State::class(fold(&set), false, out);
+                }
+                _ => {}
+            }
+        }
+        self
+    }
+
+    // Puts the given state on the heap and returns a stable
+    // identifier for that state, or fails if the NFA already
+    // has as many states as it's allowed.
+    fn alloc(&mut self, state: State) -> Result<StateID, BuildError> {
+        if self.states.len() >= self.max_states {
+            return Err(BuildError::TooManyStates);
+        }
+        // max_states can be set higher than a StateID can go
+        let id = StateID::try_from(self.states.len())
+            .map_err(|_| BuildError::TooManyStates)?;
+        self.states.push(state);
+        Ok(id)
+    }
+
+    // Perform all patch instructions such that all
+    // handles point to the state given.
+    fn patch(&mut self, l: &[ToPatch], s: StateID) {
+        for &p in l.iter() {
+            match p {
+                ToPatch::Out1(sid) => match self.states[sid as usize] {
+                    State::Literal { ref mut out, .. }
+                    | State::Any { ref mut out }
+                    | State::Class { ref mut out, .. }
+                    | State::Range { ref mut out, .. }
+                    | State::StartText { ref mut out }
+                    | State::EndText { ref mut out }
+                    | State::StartLine { ref mut out }
+                    | State::EndLine { ref mut out }
+                    | State::WordBoundary { ref mut out, .. }
+                    | State::Save { ref mut out, .. } => {
+                        *out = s;
+                    }
+                    State::Split { ref mut out1, .. } => {
+                        *out1 = s;
+                    }
+                    _ => unreachable!("invalid out1 patch"),
+                },
+                ToPatch::Out2(sid) => match self.states[sid as usize] {
+                    State::Split { ref mut out2, .. } => {
+                        *out2 = s;
+                    }
+                    _ => unreachable!("invalid out2 patch"),
+                },
+            }
+        }
+    }
+
+    // Return a human readable dump of the NFA, one state per line.
+    // Each split state is also annotated with its epsilon closure:
+    // every state reachable from it by following unlabeled arrows,
+    // without consuming any input. This is exactly the set of
+    // states that add_state_to_next visits when given that state.
+    fn dump_with_closures(&self) -> String {
+        use std::fmt::Write;
+
+        let mut dump = format!("start: {}\n", self.start);
+        for (sid, state) in self.states.iter().enumerate() {
+            // writing to a String never fails
+            let _ = match *state {
+                State::Literal { byte, out } => writeln!(
+                    dump,
+                    "{sid}: '{}' -> {out}",
+                    [byte].escape_ascii()
+                ),
+                State::Any { out } => writeln!(dump, "{sid}: any -> {out}"),
+                State::StartText { out } => {
+                    writeln!(dump, "{sid}: ^ -> {out}")
+                }
+                State::EndText { out } => writeln!(dump, "{sid}: $ -> {out}"),
+                State::StartLine { out } => {
+                    writeln!(dump, "{sid}: (?m:^) -> {out}")
+                }
+                State::EndLine { out } => {
+                    writeln!(dump, "{sid}: (?m:$) -> {out}")
+                }
+                State::WordBoundary { negate, out } => {
+                    let b = if negate { 'B' } else { 'b' };
+                    writeln!(dump, "{sid}: \\{b} -> {out}")
+                }
+                State::Save { slot, out } => {
+                    writeln!(dump, "{sid}: save {slot} -> {out}")
+                }
+                State::Class { ref set, negated, out } => {
+                    let negated = if negated { "^" } else { "" };
+                    let members = set.describe();
+                    writeln!(
+                        dump,
+                        "{sid}: class [{negated}{members}] -> {out}"
+                    )
+                }
+                State::Range { lo, hi, out } => {
+                    let (lo, hi) = (lo.escape_ascii(), hi.escape_ascii());
+                    writeln!(dump, "{sid}: range [{lo}-{hi}] -> {out}")
+                }
+                State::Split { out1, out2 } => {
+                    let closure: Vec<String> = self
+                        .epsilon_closure(sid as StateID)
+                        .iter()
+                        .map(|sid| sid.to_string())
+                        .collect();
+                    writeln!(
+                        dump,
+                        "{sid}: split -> {out1}, {out2} closure {{{}}}",
+                        closure.join(", ")
+                    )
+                }
+                State::Match => writeln!(dump, "{sid}: match"),
+            };
+        }
+        if let Some(literal) = self.as_literal() {
+            let _ = writeln!(dump, "literal: {}", literal.escape_ascii());
+        }
+        dump
+    }
+
+    // If this NFA matches exactly one string, i.e., it's just a
+    // chain of literal states ending in the match state, then
+    // return that string.
+    fn as_literal(&self) -> Option<Vec<u8>> {
+        let mut literal = vec![];
+        let mut sid = self.start;
+        // A chain can't visit more states than there are,
+        // so this is just a guard against a malformed NFA.
+        for _ in 0..self.states.len() {
+            match self.states[sid as usize] {
+                State::Literal { byte, out } => {
+                    literal.push(byte);
+                    sid = out;
+                }
+                // Saving a capture slot doesn't change what
+                // matches, and is_match doesn't report them.
+                State::Save { out, .. } => sid = out,
+                // An anchor could be skipped when it's in the
+                // right place, but it isn't worth the trouble.
+                State::Any { .. }
+                | State::Class { .. }
+                | State::Range { .. }
+                | State::StartText { .. }
+                | State::EndText { .. }
+                | State::StartLine { .. }
+                | State::EndLine { .. }
+                | State::WordBoundary { .. }
+                | State::Split { .. } => return None,
+                State::Match => return Some(literal),
+            }
+        }
+        None
+    }
+
+    // Return the set of bytes that a match can start with, found
+    // by following unlabeled arrows from the start state to the
+    // states that read a byte. For `abc|xyz`, that's a and x.
+    // Anchors are followed as if they held, so the set can be
+    // bigger than it needs to be, but never smaller. A pattern
+    // that matches the empty string can match anywhere, so then
+    // every byte is in the set.
+    fn first_byte_set(&self) -> ByteSet {
+        let mut set = ByteSet::new();
+        let mut seen = vec![false; self.states.len()];
+        let mut stack = vec![self.start];
+        while let Some(sid) = stack.pop() {
+            if std::mem::replace(&mut seen[sid as usize], true) {
+                continue;
+            }
+            let state = &self.states[sid as usize];
+            if let Some((bytes, _)) = state.transition() {
+                set.union(&bytes);
+                continue;
+            }
+            match *state {
+                State::Match => return ByteSet::new().complement(),
+                State::Split { out1, out2 } => stack.extend([out1, out2]),
+                State::StartText { out }
+                | State::EndText { out }
+                | State::StartLine { out }
+                | State::EndLine { out }
+                | State::WordBoundary { out, .. }
+                | State::Save { out, .. } => stack.push(out),
+                // these read a byte, so transition handled them
+                State::Literal { .. }
+                | State::Any { .. }
+                | State::Class { .. }
+                | State::Range { .. } => {}
+            }
+        }
+        set
+    }
+
+    // Return the sorted handles of every state reachable from
+    // the given state by following unlabeled arrows, including
+    // the state itself.
+    fn epsilon_closure(&self, sid: StateID) -> Vec<StateID> {
+        let mut seen = vec![false; self.states.len()];
+        let mut closure = vec![];
+        let mut stack = vec![sid];
+        while let Some(sid) = stack.pop() {
+            if std::mem::replace(&mut seen[sid as usize], true) {
+                continue;
+            }
+            closure.push(sid);
+            match self.states[sid as usize] {
+                State::Split { out1, out2 } => {
+                    stack.push(out2);
+                    stack.push(out1);
+                }
+                State::Save { out, .. } => stack.push(out),
+                _ => {}
+            }
+        }
+        closure.sort();
+        closure
+    }
+
+    // The number of capture slots a search of this NFA needs,
+    // which is two for each group, including group 0.
+    fn slot_count(&self) -> usize {
+        2 * self.captures_len
+    }
+
+    // Return true if a match can be reached from the given state
+    // without reading any more bytes, by following unlabeled arrows
+    // and the anchors that look says hold.
+    fn reaches_match(&self, sid: StateID, look: Look) -> bool {
+        let mut seen = vec![false; self.states.len()];
+        let mut stack = vec![sid];
+        while let Some(sid) = stack.pop() {
+            if std::mem::replace(&mut seen[sid as usize], true) {
+                continue;
+            }
+            match self.states[sid as usize] {
+                State::Match => return true,
+                State::Split { out1, out2 } => stack.extend([out1, out2]),
+                State::Save { out, .. } => stack.push(out),
+                State::StartText { out } if look.start => stack.push(out),
+                State::EndText { out } if look.end => stack.push(out),
+                State::StartLine { out } if look.start_line => stack.push(out),
+                State::EndLine { out } if look.end_line => stack.push(out),
+                State::WordBoundary { negate, out }
+                    if look.word_boundary != negate =>
+                {
+                    stack.push(out)
+                }
+                _ => {}
+            }
+        }
+        false
+    }
+
+    // For each state, return the fewest bytes that must be consumed
+    // from that state in order to reach a match, or u32::MAX if a
+    // match can't be reached at all. This is computed the first time
+    // it's asked for, which is always after the NFA is fully built.
+    fn min_remaining(&self) -> &[u32] {
+        self.min_remaining.get_or_init(|| self.shortest_paths_to_match())
+    }
+
+    // This is a shortest path search backwards from the match state,
+    // where following a literal (or any byte) costs one byte and
+    // following a split or an anchor is free.
+    fn shortest_paths_to_match(&self) -> Box<[u32]> {
+        let mut incoming: Vec<Vec<(StateID, u32)>> =
+            vec![vec![]; self.states.len()];
+        let mut min = vec![u32::MAX; self.states.len()].into_boxed_slice();
+        let mut queue = std::collections::VecDeque::new();
+        for (sid, state) in self.states.iter().enumerate() {
+            let sid = sid as StateID;
+            match *state {
+                State::Literal { .. }
+                | State::Any { .. }
+                | State::Class { .. }
+                | State::Range { .. } => {
+                    // an arrow that reads no byte at all, like the
+                    // one out of `[^\x00-\xff]`, can never be taken
+                    if let Some((bytes, out)) = state.transition() {
+                        if bytes != ByteSet::new() {
+                            incoming[out as usize].push((sid, 1));
+                        }
+                    }
+                }
+                State::StartText { out }
+                | State::EndText { out }
+                | State::StartLine { out }
+                | State::EndLine { out }
+                | State::WordBoundary { out, .. }
+                | State::Save { out, .. } => {
+                    incoming[out as usize].push((sid, 0));
+                }
+                State::Split { out1, out2 } => {
+                    incoming[out1 as usize].push((sid, 0));
+                    incoming[out2 as usize].push((sid, 0));
+                }
+                State::Match => {
+                    min[sid as usize] = 0;
+                    queue.push_back(sid);
+                }
+            }
+        }
+        // Since every edge costs either zero or one, putting free
+        // edges at the front of the queue and the rest at the back
+        // means states come off the queue in order of distance.
+        while let Some(sid) = queue.pop_front() {
+            for &(prev, cost) in incoming[sid as usize].iter() {
+                let dist = min[sid as usize] + cost;
+                if dist < min[prev as usize] {
+                    min[prev as usize] = dist;
+                    if cost == 0 {
+                        queue.push_front(prev);
+                    } else {
+                        queue.push_back(prev);
+                    }
+                }
+            }
+        }
+        min
+    }
+
+    // Return true if the haystack matches, using the given
+    // scratch space to run the search. The scratch space
+    // can be reused across any number of searches, even with
+    // other NFAs: if it's too small for this one, it's grown
+    // first, which fails only if there isn't enough memory.
+    fn is_match_with(
+        &self,
+        haystack: &[u8],
+        scratch: &mut Scratch,
+    ) -> Result<bool, TryReserveError> {
+        scratch.grow(self)?;
+        scratch.start(self, haystack, 0);
+        for (i, &byte) in haystack.iter().enumerate() {
+            // Give up as soon as no thread can possibly reach a match
+            // with the bytes that are left. For example, `abcdef` can
+            // never match `abcde`, and we can tell before reading any
+            // of it.
+            let remaining = haystack.len() - i;
+            if scratch.clist.min_remaining as usize > remaining {
+                return Ok(false);
+            }
+            scratch.step(self, byte, Look::at(haystack, i + 1));
+            std::mem::swap(&mut scratch.clist, &mut scratch.nlist);
+        }
+        Ok(scratch.has_match(self))
+    }
+}
+
+// Which match find reports when more than one
+// match starts at the leftmost possible offset.
+#[derive(Clone, Copy, Default)]
+enum MatchKind {
+    // the one a backtracking engine like Perl's would find,
+    // e.g., `a` for `a|ab` (see List)
+    #[default]
+    LeftmostFirst,
+    // the longest one, like POSIX tools report, e.g., `ab`
+    // for `a|ab`
+    Longest,
+}
+
+// Where a match has to be for a Matcher to say that a haystack
+// matches. This is set once per matcher, and is_match,
+// match_or_failure_offset and is_match_counting all follow it.
+#[derive(Clone, Copy, Default, Eq, PartialEq)]
+enum Anchoring {
+    // anywhere, as if the pattern began and ended with `.*`
+    // (see is_match_unanchored)
+    Unanchored,
+    // at the start of the haystack, but it can end anywhere
+    // (see is_prefix_match)
+    Start,
+    // the whole haystack, like in the original program
+    #[default]
+    Both,
+}
+
+// see Matcher::reset_pending
+const RESET_GENERATIONS_AT: u32 = u32::MAX / 2;
+
+// A matcher pairs an NFA with the scratch
+// space needed to search with it.
+struct Matcher {
+    // the nfa to use for matching
+    nfa: NFA,
+    // the mutable state of a search
+    scratch: Scratch,
+    // a faster matcher for small NFAs, if this one is small enough
+    bits: Option<BitMatcher>,
+    // the only string the NFA matches, if there is just one
+    literal: Option<Vec<u8>>,
+    // the bytes a match can start with (see NFA::first_byte_set)
+    first_bytes: ByteSet,
+    // the last byte given to feed, which hasn't been stepped
+    // over yet, or None if nothing has been fed since reset
+    fed: Option<u8>,
+    // which match find reports
+    kind: MatchKind,
+    // where in the haystack a match has to be
+    anchoring: Anchoring,
+    // lists of threads for captures to reuse
+    pool: Vec<Threads>,
+}
+
+impl Matcher {
+    // create a matcher for the given NFA, or return an
+    // error if there isn't enough memory for its scratch space
+    fn try_new(nfa: NFA) -> Result<Matcher, TryReserveError> {
+        let scratch = Scratch::try_new(&nfa)?;
+        let bits = BitMatcher::new(&nfa);
+        let literal = nfa.as_literal();
+        let first_bytes = nfa.first_byte_set();
+        let kind = MatchKind::default();
+        Ok(Matcher {
+            nfa,
+            scratch,
+            bits,
+            literal,
+            first_bytes,
+            fed: None,
+            kind,
+            anchoring: Anchoring::default(),
+            pool: vec![],
+        })
+    }
+
+    // Start matching a new haystack that will be given one byte
+    // at a time with feed. This reuses the scratch space, so any
+    // other search in between feeding bytes loses track of them,
+    // and reset needs to be called again.
+    //
+    // Nothing is allocated, so a matcher can be reset any number
+    // of times, no matter what state a previous search left it in.
+    fn reset(&mut self) {
+        self.scratch.reset();
+        self.fed = None;
+    }
+
+    // Whether the list IDs used to keep track of which states are
+    // on a list have run far enough that it's a good time to call
+    // reset_generations. If nobody does, the list IDs are reset
+    // anyway when they overflow, but that happens in the middle
+    // of whatever search is running, which then stalls while every
+    // state is visited.
+    fn reset_pending(&self) -> bool {
+        self.scratch.list_id >= RESET_GENERATIONS_AT
+    }
+
+    // Start the list IDs over, ahead of time, e.g., while a long
+    // running program that reuses this matcher is idle. This
+    // doesn't allocate, and it's fine to call between searches or
+    // between bytes given to feed.
+    fn reset_generations(&mut self) {
+        self.scratch.reset_generations();
+    }
+
+    // Feed the next byte of the haystack to the search.
+    //
+    // Whether `$` or `\b` holds after a byte depends on the byte
+    // after it, so each byte is only stepped over once the next
+    // one is fed. is_match_now handles the last one.
+    fn feed(&mut self, byte: u8) {
+        let (nfa, scratch) = (&self.nfa, &mut self.scratch);
+        if let Some(prev) = self.fed {
+            scratch.step(nfa, prev, Look::between(Some(prev), Some(byte)));
+            std::mem::swap(&mut scratch.clist, &mut scratch.nlist);
+        } else {
+            scratch.start(nfa, &[byte], 0);
+        }
+        self.fed = Some(byte);
+    }
+
+    // Return true if the bytes fed since the last reset match,
+    // i.e., if the haystack ended here. More bytes can be fed
+    // afterwards, which may change the answer.
+    fn is_match_now(&self) -> bool {
+        let Some(prev) = self.fed else {
+            let look = Look::between(None, None);
+            return self.nfa.reaches_match(self.nfa.start, look);
+        };
+        let (nfa, clist) = (&self.nfa, &self.scratch.clist);
+        let look = Look::between(Some(prev), None);
+        clist.s[..clist.n].iter().any(|&sid| {
+            nfa.states[sid as usize]
+                .next(prev)
+                .is_some_and(|out| nfa.reaches_match(out, look))
+        })
+    }
+
+    // Return true if no bytes fed from now on can lead to a
+    // match, because every thread has died.
+    fn is_dead(&self) -> bool {
+        self.fed.is_some() && self.scratch.clist.n == 0
+    }
+
+    // Return true if the haystack matches, wherever the matcher's
+    // anchoring says the match has to be. Like every search that
+    // takes the whole haystack, this starts by calling start on the
+    // scratch space, which clears out whatever an earlier search
+    // left in it. So there's no need to reset between searches.
+    // This can only fail like is_match_with can, which it never
+    // does here since the scratch space was made for this NFA.
+    fn is_match(&mut self, haystack: &[u8]) -> Result<bool, TryReserveError> {
+        match self.anchoring {
+            Anchoring::Unanchored => {
+                return Ok(self.is_match_unanchored(haystack));
+            }
+            Anchoring::Start => return Ok(self.is_prefix_match(haystack)),
+            Anchoring::Both => {}
+        }
+        // Matching a literal is just a comparison.
+        if let Some(ref literal) = self.literal {
+            return Ok(haystack == literal.as_slice());
+        }
+        if let Some(ref bits) = self.bits {
+            return Ok(bits.is_match(haystack));
+        }
+        self.nfa.is_match_with(haystack, &mut self.scratch)
+    }
+
+    // Like is_match, but return true if the pattern matches
+    // anywhere in the haystack, as if it began and ended with
+    // `.*`. Instead of building a bigger NFA, a new thread is
+    // started at every position by adding the start state to
+    // nlist after each step. Nothing needs to come after a
+    // match, so the search stops at the first one. The other
+    // unanchored searches start threads the same way.
+    //
+    // Whenever every thread has died, there's no need to start
+    // new ones until the next byte a match can start with, so
+    // the search skips straight there.
+    fn is_match_unanchored(&mut self, haystack: &[u8]) -> bool {
+        if let Some(ref literal) = self.literal {
+            return literal.is_empty()
+                || haystack.windows(literal.len()).any(|w| w == literal);
+        }
+        let (nfa, scratch) = (&self.nfa, &mut self.scratch);
+        let mut at = self.first_bytes.find_in(haystack, 0);
+        scratch.start(nfa, haystack, at);
+        loop {
+            if scratch.has_match(nfa) {
+                return true;
+            }
+            if at == haystack.len() {
+                return false;
+            }
+            let look = Look::at(haystack, at + 1);
+            scratch.step(nfa, haystack[at], look);
+            at += 1;
+            if scratch.nlist.n == 0 {
+                at = self.first_bytes.find_in(haystack, at);
+                scratch.start(nfa, haystack, at);
+            } else {
+                scratch.add_state_to_next(nfa, nfa.start, look, at);
+                std::mem::swap(&mut scratch.clist, &mut scratch.nlist);
+            }
+        }
+    }
+
+    // Like is_match, but read the haystack from the given reader
+    // instead of needing all of it in memory. It's read in chunks
+    // into a buffer, and each byte is fed to the search in turn.
+    fn is_match_reader<R: std::io::Read>(
+        &mut self,
+        mut reader: R,
+    ) -> std::io::Result<bool> {
+        let mut buf = vec![0; 8 * 1024];
+        self.reset();
+        loop {
+            let n = match reader.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(err) if err.kind() == std::io::ErrorKind::Interrupted => {
+                    continue;
+                }
+                Err(err) => return Err(err),
+            };
+            for &byte in &buf[..n] {
+                self.feed(byte);
+                // the rest of the reader can't change the answer
+                if self.is_dead() {
+                    return Ok(false);
+                }
+            }
+        }
+        Ok(self.is_match_now())
+    }
+
+    // Like is_match, but when the haystack doesn't match, report
+    // how far the search got. That's the offset of the byte at
+    // which every thread died, or the length of the haystack if
+    // some threads survived to the end without reaching a match.
+    // An unanchored search starts a new thread after every byte,
+    // so unless the pattern is anchored, it only fails at the end.
+    fn match_or_failure_offset(
+        &mut self,
+        haystack: &[u8],
+    ) -> Result<(), usize> {
+        let (nfa, scratch) = (&self.nfa, &mut self.scratch);
+        let anchoring = self.anchoring;
+        scratch.start(nfa, haystack, 0);
+        for (i, &byte) in haystack.iter().enumerate() {
+            if anchoring != Anchoring::Both && scratch.has_match(nfa) {
+                return Ok(());
+            }
+            let look = Look::at(haystack, i + 1);
+            scratch.step(nfa, byte, look);
+            if anchoring == Anchoring::Unanchored {
+                scratch.add_state_to_next(nfa, nfa.start, look, i + 1);
+            }
+            std::mem::swap(&mut scratch.clist, &mut scratch.nlist);
+            if scratch.clist.n == 0 {
+                return Err(i);
+            }
+        }
+        if scratch.has_match(nfa) {
+            Ok(())
+        } else {
+            Err(haystack.len())
+        }
+    }
+
+    // Search for the pattern anywhere in the haystack, and return
+    // the start and end offsets of the leftmost match. When there
+    // is more than one match starting there, the matcher's kind
+    // picks one. By default, the one preferred by the pattern
+    // wins, e.g., the longest for `a+` or the first alternative
+    // that can match for `a|ab`. (This is usually called
+    // leftmost-first.) With MatchKind::Longest, the longest wins.
+    //
+    // Threads are kept in the lists in order of preference, and
+    // add_state_to_next follows out1 before out2, which is the
+    // preferred arrow of every split. A thread that starts later
+    // is added after every thread that started earlier, so it's
+    // less preferred than all of them.
+    fn find(&mut self, haystack: &[u8]) -> Option<(usize, usize)> {
+        self.find_at(haystack, 0)
+    }
+
+    // Like find, but return the bytes of the match instead of where
+    // it is. They borrow from the haystack and not from the matcher,
+    // so the matcher can be used again while they're still around.
+    fn find_bytes<'h>(&mut self, haystack: &'h [u8]) -> Option<&'h [u8]> {
+        self.find(haystack).map(|(start, end)| &haystack[start..end])
+    }
+
+    // Like find, but skip the first `start` bytes of the haystack.
+    // They're still used to decide whether anchors hold, so `\bb`
+    // doesn't match in `ab` starting at 1, and `^a` only matches
+    // when start is 0.
+    fn find_at(
+        &mut self,
+        haystack: &[u8],
+        start: usize,
+    ) -> Option<(usize, usize)> {
+        let (nfa, scratch) = (&self.nfa, &mut self.scratch);
+        let mut found = None;
+        let mut at = self.first_bytes.find_in(haystack, start);
+        scratch.start(nfa, haystack, at);
+        loop {
+            // The first thread to reach a match beats every thread
+            // after it, so they can be dropped. The threads before
+            // it might still find a match they prefer.
+            //
+            // When the longest match wins instead, only the threads
+            // that started later can be dropped. Since new threads
+            // are added last, threads are in order of where they
+            // started. Any thread that is left and reaches a match
+            // later either started further left or matches more.
+            let clist = &mut scratch.clist;
+            let matched = (0..clist.n).find(|&i| {
+                matches!(nfa.states[clist.s[i] as usize], State::Match)
+            });
+            if let Some(i) = matched {
+                let start = clist.starts[i];
+                found = Some((start, at));
+                clist.n = match self.kind {
+                    MatchKind::LeftmostFirst => i,
+                    MatchKind::Longest => clist.starts[..clist.n]
+                        .partition_point(|&s| s <= start),
+                };
+            }
+            if at == haystack.len() || (clist.n == 0 && found.is_some()) {
+                return found;
+            }
+            let look = Look::at(haystack, at + 1);
+            scratch.step(nfa, haystack[at], look);
+            at += 1;
+            // Once there's a match, a thread starting here
+            // would be further right, so don't start one.
+            // Without any threads left, skip to where the
+            // next one could start, like is_match_unanchored.
+            if found.is_some() {
+                std::mem::swap(&mut scratch.clist, &mut scratch.nlist);
+            } else if scratch.nlist.n == 0 {
+                at = self.first_bytes.find_in(haystack, at);
+                scratch.start(nfa, haystack, at);
+            } else {
+                scratch.add_state_to_next(nfa, nfa.start, look, at);
+                std::mem::swap(&mut scratch.clist, &mut scratch.nlist);
+            }
+        }
+    }
+
+    // The number of groups in the pattern, counting group 0 for the
+    // whole match. So `(a)(b(c))` has 4. Each group gets two of the
+    // slots that captures returns.
+    fn captures_len(&self) -> usize {
+        self.nfa.captures_len
+    }
+
+    // Search for the leftmost-first match like find does, and
+    // return its capture slots. Slots 2i and 2i+1 hold where
+    // group i starts and ends, where group 0 is the whole match.
+    // Both are None for a group that took no part in the match,
+    // like the second group in `(a)|(b)` matching `a`.
+    //
+    // When the matcher's kind is Longest, this finds the longest
+    // match and picks its groups the way POSIX does instead (see
+    // captures_posix).
+    //
+    // This is a Pike VM. It's the same simulation as find, except
+    // that each thread carries its own copy of the slots, and a
+    // thread that passes through a Save state records the current
+    // offset in its copy. Threads are kept in order of priority,
+    // so when two threads reach the same state, the slots of the
+    // preferred one are the ones that are kept.
+    fn captures(&mut self, haystack: &[u8]) -> Option<Vec<Option<usize>>> {
+        if let MatchKind::Longest = self.kind {
+            return self.captures_posix(haystack);
+        }
+        let mut clist = self.take_threads();
+        let mut nlist = self.take_threads();
+        let nfa = &self.nfa;
+        let nslots = nfa.slot_count();
+        let mut seen = vec![false; nfa.states.len()];
+        let mut slots = vec![None; nslots];
+        let mut found = None;
+        slots[0] = Some(0);
+        let look = Look::at(haystack, 0);
+        clist.add(nfa, &mut seen, nfa.start, look, 0, &mut slots);
+        let mut at = 0;
+        let found = loop {
+            let matched = clist.s.iter().position(|&sid| {
+                matches!(nfa.states[sid as usize], State::Match)
+            });
+            if let Some(i) = matched {
+                let mut caps = clist.slots(i).to_vec();
+                caps[1] = Some(at);
+                found = Some(caps);
+                clist.truncate(i);
+            }
+            if at == haystack.len() || (clist.s.is_empty() && found.is_some())
+            {
+                break found;
+            }
+            let look = Look::at(haystack, at + 1);
+            nlist.clear();
+            seen.fill(false);
+            for i in 0..clist.s.len() {
+                let state = &nfa.states[clist.s[i] as usize];
+                if let Some(out) = state.next(haystack[at]) {
+                    slots.copy_from_slice(clist.slots(i));
+                    nlist.add(nfa, &mut seen, out, look, at + 1, &mut slots);
+                }
+            }
+            at += 1;
+            if found.is_none() {
+                slots.fill(None);
+                slots[0] = Some(at);
+                nlist.add(nfa, &mut seen, nfa.start, look, at, &mut slots);
+            }
+            std::mem::swap(&mut clist, &mut nlist);
+        };
+        self.put_threads(clist);
+        self.put_threads(nlist);
+        found
+    }
+
+    // Like captures, but for the leftmost-longest match, with
+    // groups picked by the POSIX rule: going through the groups
+    // in order, each one starts as early and then ends as late
+    // as it can, given the match and the groups before it. So
+    // `(a|ab)(c|bcd)(d*)` matching `abcd` gives `ab`, `c` and
+    // `d`, where captures would give `a`, `bcd` and ``.
+    //
+    // In captures, when two threads reach the same state, the
+    // first one to get there wins. Here the winner is the one
+    // whose slots are better by that rule instead (see
+    // posix_better), which may well be the second one, and then
+    // the states after it have to be reached again with its
+    // slots. Closure takes care of that.
+    //
+    // For a group in a repetition, the slots hold its last
+    // iteration, and the rule is applied to that. POSIX has more
+    // to say about repetitions than that, and C libraries don't
+    // agree on what it means, so this doesn't try to follow it
+    // any further.
+    fn captures_posix(
+        &mut self,
+        haystack: &[u8],
+    ) -> Option<Vec<Option<usize>>> {
+        let mut list = self.take_threads();
+        let nfa = &self.nfa;
+        let nslots = nfa.slot_count();
+        let mut closure = Closure::new(nfa.states.len(), nslots);
+        let mut slots = vec![None; nslots];
+        let mut found: Option<Vec<Option<usize>>> = None;
+        slots[0] = Some(0);
+        closure.reach(nfa.start, &slots);
+        closure.follow(nfa, Look::at(haystack, 0), 0);
+        closure.finish(nfa, &mut list);
+        let mut at = 0;
+        let found = loop {
+            let matched = list.s.iter().position(|&sid| {
+                matches!(nfa.states[sid as usize], State::Match)
+            });
+            if let Some(i) = matched {
+                // A match that starts later than the one we
+                // have isn't leftmost. Otherwise, it's longer.
+                let start = list.slots(i)[0];
+                if found.as_ref().is_none_or(|caps| start <= caps[0]) {
+                    let mut caps = list.slots(i).to_vec();
+                    caps[1] = Some(at);
+                    found = Some(caps);
+                }
+            }
+            if let Some(ref caps) = found {
+                // only threads that started no later than the
+                // match can still make it longer
+                list.retain(|slots| slots[0] <= caps[0]);
+            }
+            if at == haystack.len() || (list.s.is_empty() && found.is_some()) {
+                break found;
+            }
+            for i in 0..list.s.len() {
+                let state = &nfa.states[list.s[i] as usize];
+                if let Some(out) = state.next(haystack[at]) {
+                    closure.reach(out, list.slots(i));
+                }
+            }
+            at += 1;
+            if found.is_none() {
+                slots.fill(None);
+                slots[0] = Some(at);
+                closure.reach(nfa.start, &slots);
+            }
+            closure.follow(nfa, Look::at(haystack, at), at);
+            list.clear();
+            closure.finish(nfa, &mut list);
+        };
+        self.put_threads(list);
+        found
+    }
+
+    // Take a list of threads for captures from the pool, or make
+    // a new one if the pool is empty. Reusing lists saves having
+    // to allocate room for their slots again on every search.
+    fn take_threads(&mut self) -> Threads {
+        self.pool.pop().unwrap_or_else(|| Threads::new(self.nfa.slot_count()))
+    }
+
+    // Put a list of threads back in the pool, emptied, so that no
+    // slots from this search can show up in the next one.
+    fn put_threads(&mut self, mut threads: Threads) {
+        threads.clear();
+        self.pool.push(threads);
+    }
+
+    // Return the length of the shortest prefix of the haystack
+    // that matches, or None if no prefix does. This is cheaper
+    // than working out the span of a match, since the search can
+    // stop as soon as any thread reaches the match state, which
+    // makes it handy when all that matters is whether there is a
+    // match at the start of the haystack.
+    fn shortest_match(&mut self, haystack: &[u8]) -> Option<usize> {
+        let (nfa, scratch) = (&self.nfa, &mut self.scratch);
+        scratch.start(nfa, haystack, 0);
+        for at in 0..=haystack.len() {
+            if scratch.has_match(nfa) {
+                return Some(at);
+            }
+            if at == haystack.len() || scratch.clist.n == 0 {
+                break;
+            }
+            scratch.step(nfa, haystack[at], Look::at(haystack, at + 1));
+            std::mem::swap(&mut scratch.clist, &mut scratch.nlist);
+        }
+        None
+    }
+
+    // Return true if some prefix of the haystack matches, i.e.,
+    // the match has to start at the beginning of the haystack but
+    // doesn't have to reach the end. That's the same as asking if
+    // the search reaches the match state at any offset at all.
+    fn is_prefix_match(&mut self, haystack: &[u8]) -> bool {
+        self.shortest_match(haystack).is_some()
+    }
+
+    // Return the span of every match in the haystack, including
+    // ones that overlap, ordered by start and then by end. For
+    // example, `a+` has six matches in `aaa`.
+    //
+    // Threads that started at different offsets merge as soon as
+    // they reach the same state, which is what keeps the usual
+    // simulation linear, but then it can't tell which starts each
+    // match belongs to. So instead, this runs an anchored search
+    // from each offset in turn, and doesn't stop at the first
+    // match. That's quadratic in the length of the haystack.
+    fn find_overlapping(&mut self, haystack: &[u8]) -> Vec<(usize, usize)> {
+        let (nfa, scratch) = (&self.nfa, &mut self.scratch);
+        let mut spans = vec![];
+        for start in 0..=haystack.len() {
+            scratch.start(nfa, haystack, start);
+            for at in start..=haystack.len() {
+                if scratch.has_match(nfa) {
+                    spans.push((start, at));
+                }
+                if at == haystack.len() || scratch.clist.n == 0 {
+                    break;
+                }
+                scratch.step(nfa, haystack[at], Look::at(haystack, at + 1));
+                std::mem::swap(&mut scratch.clist, &mut scratch.nlist);
+            }
+        }
+        spans
+    }
+
+    // Return an iterator over the spans of all non-overlapping
+    // matches in the haystack, from left to right.
+    fn find_iter<'h>(&'h mut self, haystack: &'h [u8]) -> Matches<'h> {
+        Matches { matcher: self, haystack, at: 0 }
+    }
+
+    // Like find_iter, but yield each match's bytes along with
+    // where it is.
+    fn find_iter_full<'h>(
+        &'h mut self,
+        haystack: &'h [u8],
+    ) -> impl Iterator<Item = Match<'h>> {
+        self.find_iter(haystack).map(|(start, end)| Match {
+            start,
+            end,
+            bytes: &haystack[start..end],
+        })
+    }
+
+    // Return how many matches find_iter reports, in a single pass
+    // over the haystack instead of a new search after each match.
+    //
+    // Restarting can cost a lot, because a search may read well
+    // past the end of the match it reports. For example, `x+y|x`
+    // reads all of `xxxx` only to report `x`, and the next search
+    // reads the rest of it again. Instead, the searches run side
+    // by side on the same lists. A search starts where the match
+    // of the one before it ends, so each thread belongs to the
+    // search whose match is the first to end after the thread
+    // started. Since new threads are added last, the threads are
+    // in order of search. When a thread reaches a match, the same
+    // cut as in find_at drops the threads after it, which belong
+    // to its own search or to later ones. The later ones started
+    // before the new end of the match, so they were wrong anyway.
+    //
+    // A thread of an earlier search can take a state away from a
+    // thread of a later one. That's fine: from the same state,
+    // both have the same future, and if it leads to a match, the
+    // earlier search's match gets longer, which drops the later
+    // search's threads either way.
+    fn count_matches_in(&mut self, haystack: &[u8]) -> usize {
+        // Record the first match in list.s[from..] as the match
+        // of the search its thread belongs to, and cut off the
+        // threads it beats, returning whether there was one.
+        fn record(
+            nfa: &NFA,
+            kind: MatchKind,
+            list: &mut List,
+            from: usize,
+            at: usize,
+            pending: &mut VecDeque<usize>,
+        ) -> bool {
+            let Some(i) = (from..list.n).find(|&i| {
+                matches!(nfa.states[list.s[i] as usize], State::Match)
+            }) else {
+                return false;
+            };
+            let start = list.starts[i];
+            // This replaces the search's own match, if it had
+            // one, and forgets every search after it.
+            let search = pending.partition_point(|&next| next <= start);
+            pending.truncate(search);
+            // like find_iter, skip a byte past an empty match
+            pending.push_back(if start == at { at + 1 } else { at });
+            let n = match kind {
+                MatchKind::LeftmostFirst => i,
+                MatchKind::Longest => {
+                    list.starts[..list.n].partition_point(|&s| s <= start)
+                }
+            };
+            // Unlike in find_at, threads that have reached a match
+            // can't stay behind. They'd stop the next search from
+            // adding a match state of its own here.
+            list.n = i;
+            for j in i..n {
+                if !matches!(nfa.states[list.s[j] as usize], State::Match) {
+                    list.s[list.n] = list.s[j];
+                    list.starts[list.n] = list.starts[j];
+                    list.n += 1;
+                }
+            }
+            true
+        }
+
+        let (nfa, scratch, kind) = (&self.nfa, &mut self.scratch, self.kind);
+        // For each search with a match that could still change,
+        // where the search after it starts. In order of search.
+        let mut pending = VecDeque::new();
+        // where the search after the last settled one starts
+        let mut next = 0;
+        let mut count = 0;
+        scratch.start(nfa, haystack, 0);
+        record(nfa, kind, &mut scratch.clist, 0, 0, &mut pending);
+        let mut at = 0;
+        loop {
+            // A search's match is settled once its threads are gone.
+            while let Some(&end) = pending.front() {
+                let clist = &scratch.clist;
+                if clist.n > 0 && clist.starts[0] < end {
+                    break;
+                }
+                pending.pop_front();
+                next = end;
+                count += 1;
+            }
+            if at == haystack.len() {
+                return count + pending.len();
+            }
+            let look = Look::at(haystack, at + 1);
+            scratch.step(nfa, haystack[at], look);
+            at += 1;
+            let cut =
+                record(nfa, kind, &mut scratch.nlist, 0, at, &mut pending);
+            // The newest search starts threads until it finds a
+            // match. After that, threads belong to the next one.
+            if at >= pending.back().copied().unwrap_or(next) {
+                if cut {
+                    scratch.forget_dropped();
+                }
+                let from = scratch.nlist.n;
+                scratch.add_state_to_next(nfa, nfa.start, look, at);
+                record(nfa, kind, &mut scratch.nlist, from, at, &mut pending);
+            }
+            std::mem::swap(&mut scratch.clist, &mut scratch.nlist);
+        }
+    }
+
+    // Return a copy of the haystack with every non-overlapping
+    // match replaced by the given bytes, and everything between
+    // the matches copied as is. An empty match, like one of `\b`,
+    // just inserts the replacement. find_iter moves past it, so
+    // the same spot isn't found again.
+    fn replace_all(&mut self, haystack: &[u8], replacement: &[u8]) -> Vec<u8> {
+        self.replacen(haystack, usize::MAX, replacement)
+    }
+
+    // Like replace_all, but only replace the first n matches, and
+    // copy the rest of the haystack as is. When n is 0, that's a
+    // copy of the whole haystack.
+    fn replacen(
+        &mut self,
+        haystack: &[u8],
+        n: usize,
+        replacement: &[u8],
+    ) -> Vec<u8> {
+        let mut out = Vec::with_capacity(haystack.len());
+        let mut last = 0;
+        for (start, end) in self.find_iter(haystack).take(n) {
+            out.extend_from_slice(&haystack[last..start]);
+            out.extend_from_slice(replacement);
+            last = end;
+        }
+        out.extend_from_slice(&haystack[last..]);
+        out
+    }
+
+    // Return the pieces of the haystack between non-overlapping
+    // matches, like str::split. A match at either end of the
+    // haystack leaves an empty piece there, and so do two matches
+    // right next to each other.
+    fn split<'h>(&mut self, haystack: &'h [u8]) -> Vec<&'h [u8]> {
+        let mut pieces = vec![];
+        let mut last = 0;
+        for (start, end) in self.find_iter(haystack) {
+            pieces.push(&haystack[last..start]);
+            last = end;
+        }
+        pieces.push(&haystack[last..]);
+        pieces
+    }
+
+    // Like split, but from the right and into at most limit pieces,
+    // like str::rsplitn. The pieces come last to first, and once
+    // there are limit - 1 of them, the last piece is everything to
+    // the left, matches and all. A limit of 0 gives no pieces.
+    //
+    // The matches are still the ones find_iter finds from left to
+    // right, so this splits at the same places split does. Only
+    // which ones are used is decided from the right.
+    fn rsplitn<'h>(
+        &mut self,
+        haystack: &'h [u8],
+        limit: usize,
+    ) -> Vec<&'h [u8]> {
+        if limit == 0 {
+            return vec![];
+        }
+        let spans: Vec<(usize, usize)> = self.find_iter(haystack).collect();
+        let mut pieces = vec![];
+        let mut end = haystack.len();
+        for &(start, last) in spans.iter().rev().take(limit - 1) {
+            pieces.push(&haystack[last..end]);
+            end = start;
+        }
+        pieces.push(&haystack[..end]);
+        pieces
+    }
+
+    // Like is_match, but also return the total number of states
+    // stepped over across the whole search. Each byte costs at
+    // most one step per NFA state, so this never exceeds
+    // len(haystack) * len(states). This always runs the plain
+    // list simulation, without giving up early, so that the count
+    // reflects the worst case work of the algorithm.
+    fn is_match_counting(&mut self, haystack: &[u8]) -> (bool, u64) {
+        let (nfa, scratch) = (&self.nfa, &mut self.scratch);
+        let (mut matched, mut steps) = (false, 0);
+        scratch.start(nfa, haystack, 0);
+        let anchoring = self.anchoring;
+        for (i, &byte) in haystack.iter().enumerate() {
+            // a match not anchored at the end can end anywhere
+            matched |= anchoring != Anchoring::Both && scratch.has_match(nfa);
+            steps += scratch.clist.n as u64;
+            let look = Look::at(haystack, i + 1);
+            scratch.step(nfa, byte, look);
+            if anchoring == Anchoring::Unanchored {
+                scratch.add_state_to_next(nfa, nfa.start, look, i + 1);
+            }
+            std::mem::swap(&mut scratch.clist, &mut scratch.nlist);
+        }
+        (matched || scratch.has_match(nfa), steps)
+    }
+}
+
+// Split what a reader gives into lines, so that they can be
+// searched one at a time as they arrive, without waiting for the
+// rest, e.g., when tailing a log. The reads come in chunks that
+// can end in the middle of a line, so the part of a line after
+// the last '\n' is held on to until the rest of it arrives. A
+// last line with no '\n' after it still counts once the reader
+// runs out.
+struct LineGrep<R> {
+    reader: R,
+    // bytes read but not yet handed out as lines, after
+    // the first start bytes, which have been
+    buf: Vec<u8>,
+    start: usize,
+    // how many bytes after start are known not to be '\n'
+    scanned: usize,
+    // whether the reader has run out
+    eof: bool,
+}
+
+impl<R: std::io::Read> LineGrep<R> {
+    fn new(reader: R) -> LineGrep<R> {
+        LineGrep { reader, buf: vec![], start: 0, scanned: 0, eof: false }
+    }
+
+    // Return the next line that the matcher matches, without its
+    // '\n', or None once there are no lines left. The line borrows
+    // from the buffer, so it only lasts until the next call.
+    fn next_match(
+        &mut self,
+        matcher: &mut Matcher,
+    ) -> std::io::Result<Option<&[u8]>> {
+        while let Some(line) = self.next_line()? {
+            let matched = matcher
+                .is_match(&self.buf[line.clone()])
+                .map_err(std::io::Error::other)?;
+            if matched {
+                return Ok(Some(&self.buf[line]));
+            }
+        }
+        Ok(None)
+    }
+
+    // Return where the next line is in the buffer, reading more
+    // until there's a whole line to return, or None once there
+    // are no lines left.
+    fn next_line(&mut self) -> std::io::Result<Option<Range<usize>>> {
+        loop {
+            let rest = &self.buf[self.start..];
+            if let Some(i) =
+                rest[self.scanned..].iter().position(|&b| b == b'\n')
+            {
+                let line = self.start..self.start + self.scanned + i;
+                (self.start, self.scanned) = (line.end + 1, 0);
+                return Ok(Some(line));
+            }
+            self.scanned = rest.len();
+            // The lines before start were all handed out, so
+            // only the unfinished one after them is kept.
+            self.buf.drain(..self.start);
+            self.start = 0;
+            if self.eof {
+                if self.buf.is_empty() {
+                    return Ok(None);
+                }
+                (self.start, self.scanned) = (self.buf.len(), 0);
+                return Ok(Some(0..self.buf.len()));
+            }
+            let len = self.buf.len();
+            self.buf.resize(len + 8 * 1024, 0);
+            match self.reader.read(&mut self.buf[len..]) {
+                Ok(n) => {
+                    self.buf.truncate(len + n);
+                    self.eof = n == 0;
+                }
+                Err(err) => {
+                    self.buf.truncate(len);
+                    if err.kind() != std::io::ErrorKind::Interrupted {
+                        return Err(err);
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Scratch space for searching with one NFA from many threads at
+// once. A Matcher can only run one search at a time, since it has
+// a single scratch space. A pool instead hands out scratch space
+// to each search, and takes it back afterwards for the next one,
+// so there's never more of it than there are searches running at
+// the same time.
+//
+// The NFA is behind an Arc, so it can be shared with other pools,
+// or anything else, without being copied.
+struct MatcherPool {
+    nfa: Arc<NFA>,
+    // scratch space that no search is using
+    scratch: Mutex<Vec<Scratch>>,
+}
+
+impl MatcherPool {
+    // create a pool for the given NFA, with no scratch space yet
+    fn new(nfa: Arc<NFA>) -> MatcherPool {
+        MatcherPool { nfa, scratch: Mutex::new(vec![]) }
+    }
+
+    // Return true if the haystack matches, like Matcher::is_match
+    // with its default settings. This fails only if there's no
+    // scratch space to spare and not enough memory to make more.
+    //
+    // The lock is only held while taking scratch space out of
+    // the pool and putting it back, not during the search.
+    fn is_match(&self, haystack: &[u8]) -> Result<bool, TryReserveError> {
+        let mut scratch = match self.lock().pop() {
+            Some(scratch) => scratch,
+            None => Scratch::try_new(&self.nfa)?,
+        };
+        let matched = self.nfa.is_match_with(haystack, &mut scratch);
+        self.lock().push(scratch);
+        matched
+    }
+
+    // Lock the pool. A thread that panics while holding the
+    // lock can't leave the list of scratch space half updated,
+    // so a poisoned lock is fine to use.
+    fn lock(&self) -> std::sync::MutexGuard<'_, Vec<Scratch>> {
+        self.scratch.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+// A match found in a haystack, which is haystack[start..end].
+struct Match<'h> {
+    start: usize,
+    end: usize,
+    bytes: &'h [u8],
+}
+
+// An iterator over the spans of non-overlapping matches in a
+// haystack, created by Matcher::find_iter. Each search resumes
+// where the last match ended.
+//
+// The iterator borrows both the matcher and the haystack for 'h.
+// The matcher is borrowed mutably since its scratch space is used
+// for each search, so it can't be used for anything else until
+// the iterator is dropped. A single lifetime is enough for both:
+// 'h just shrinks to whichever of the two borrows ends first.
+struct Matches<'h> {
+    matcher: &'h mut Matcher,
+    haystack: &'h [u8],
+    // where the next search begins, which is past the
+    // end of the haystack once there are no more matches
+    at: usize,
+}
+
+impl Iterator for Matches<'_> {
+    type Item = (usize, usize);
+
+    fn next(&mut self) -> Option<(usize, usize)> {
+        if self.at > self.haystack.len() {
+            return None;
+        }
+        let Some((start, end)) = self.matcher.find_at(self.haystack, self.at)
+        else {
+            self.at = self.haystack.len() + 1;
+            return None;
+        };
+        // An empty match would be found again at the same
+        // offset forever, so skip a byte past it instead.
+        self.at = if start == end { end + 1 } else { end };
+        Some((start, end))
+    }
+}
+
+// A bit-parallel version of the Thompson NFA simulation for
+// NFAs with at most 64 states. Instead of lists of state
+// handles, a set of states is a u64 with one bit per state.
+// Following unlabeled arrows is done ahead of time, so that
+// each step is just a few bitwise operations per state in
+// the current set, with no lists or list IDs to maintain.
+struct BitMatcher {
+    // the epsilon closure of the start state
+    start: u64,
+    // for each byte, the literal states that match it
+    byte_states: Box<[u64; 256]>,
+    // for each state, the epsilon closure of the
+    // state it transitions to (only set for literals)
+    follow: Box<[u64]>,
+    // the set containing just the match state(s)
+    matches: u64,
+    // for each number of bytes n, the states from which
+    // a match can be reached by consuming at most n bytes
+    within: Box<[u64]>,
+}
+
+impl BitMatcher {
+    // build a bit-parallel matcher for the given NFA, or return
+    // None if it has too many states or if it has anchors, which
+    // can't be followed ahead of time
+    fn new(nfa: &NFA) -> Option<BitMatcher> {
+        if nfa.states.len() > 64 {
+            return None;
+        }
+        let closure = |sid: StateID| {
+            nfa.epsilon_closure(sid).iter().fold(0, |set, &s| set | (1 << s))
+        };
+        let mut byte_states = Box::new([0; 256]);
+        let mut follow = vec![0; nfa.states.len()].into_boxed_slice();
+        let mut matches = 0;
+        // A path to a match never needs to revisit a state, so
+        // every state that can reach a match at all can do so
+        // in fewer than 64 bytes.
+        let mut within = vec![0; 64].into_boxed_slice();
+        for (sid, &min) in nfa.min_remaining().iter().enumerate() {
+            for set in within.iter_mut().skip(min as usize) {
+                *set |= 1 << sid;
+            }
+        }
+        for (sid, state) in nfa.states.iter().enumerate() {
+            match *state {
+                State::Literal { byte, out } => {
+                    byte_states[usize::from(byte)] |= 1 << sid;
+                    follow[sid] = closure(out);
+                }
+                State::Any { out } => {
+                    for states in byte_states.iter_mut() {
+                        *states |= 1 << sid;
                     }
-                    State::Split { ref mut out1, .. } => {
-                        *out1 = s;
+                    follow[sid] = closure(out);
+                }
+                State::Class { ref set, negated, out } => {
+                    for byte in
+                        (0..=255).filter(|&b| set.contains(b) != negated)
+                    {
+                        byte_states[usize::from(byte)] |= 1 << sid;
                     }
-                    _ => unreachable!("invalid out1 patch"),
-                },
-                ToPatch::Out2(sid) => match self.states[sid as usize] {
-                    State::Split { ref mut out2, .. } => {
-                        *out2 = s;
+                    follow[sid] = closure(out);
+                }
+                State::Range { lo, hi, out } => {
+                    for byte in lo..=hi {
+                        byte_states[usize::from(byte)] |= 1 << sid;
                     }
-                    _ => unreachable!("invalid out2 patch"),
-                },
+                    follow[sid] = closure(out);
+                }
+                State::StartText { .. }
+                | State::EndText { .. }
+                | State::StartLine { .. }
+                | State::EndLine { .. }
+                | State::WordBoundary { .. } => {
+                    return None;
+                }
+                State::Split { .. } | State::Save { .. } => {}
+                State::Match => matches |= 1 << sid,
+            }
+        }
+        Some(BitMatcher {
+            start: closure(nfa.start),
+            byte_states,
+            follow,
+            matches,
+            within,
+        })
+    }
+
+    // return true if the haystack matches
+    fn is_match(&self, haystack: &[u8]) -> bool {
+        let mut set = self.start;
+        for (i, &byte) in haystack.iter().enumerate() {
+            // give up if nothing in the set can reach
+            // a match with the bytes that are left
+            let remaining = (haystack.len() - i).min(self.within.len() - 1);
+            if set & self.within[remaining] == 0 {
+                return false;
+            }
+            // the states in the current set that can step over this byte
+            let mut stepped = set & self.byte_states[usize::from(byte)];
+            set = 0;
+            while stepped != 0 {
+                set |= self.follow[stepped.trailing_zeros() as usize];
+                // clear the lowest set bit
+                stepped &= stepped - 1;
             }
         }
+        set & self.matches != 0
     }
 }
 
-// A matcher encapsulates the state
-// of searching for a regex match.
-struct Matcher {
-    // the nfa to use for matching
-    nfa: NFA,
+// Scratch space encapsulates the mutable state of searching
+// for a regex match. It is kept separate from the NFA so that
+// the NFA stays immutable once it's built, and so that the
+// lists can be allocated once and then reused.
+struct Scratch {
     // first or "current" list
     clist: List,
     // second or "next" list
@@ -340,51 +3558,444 @@ struct Matcher {
     list_id: u32,
     // map from state handle to list ID
     last_list_id: Box<[u32]>,
+    // states that add_state_to_next has yet to visit
+    stack: Vec<StateID>,
+}
+
+// The anchors that hold at some position in a haystack.
+#[derive(Clone, Copy)]
+struct Look {
+    // the position is the start of the haystack
+    start: bool,
+    // the position is the end of the haystack
+    end: bool,
+    // the position is the start of the haystack or right after a '\n'
+    start_line: bool,
+    // the position is the end of the haystack or right before a '\n'
+    end_line: bool,
+    // the position is between a word byte and a non-word
+    // byte, where the ends of the haystack count as non-word
+    word_boundary: bool,
+}
+
+impl Look {
+    // the anchors that hold at the given offset in the haystack
+    fn at(haystack: &[u8], at: usize) -> Look {
+        let before = at.checked_sub(1).map(|i| haystack[i]);
+        Look::between(before, haystack.get(at).copied())
+    }
+
+    // the anchors that hold between the given bytes, where None
+    // means that side is an end of the haystack
+    fn between(before: Option<u8>, after: Option<u8>) -> Look {
+        // a word byte is anything \w matches
+        let is_word = |b: Option<u8>| {
+            b.is_some_and(|b| b.is_ascii_alphanumeric() || b == b'_')
+        };
+        Look {
+            start: before.is_none(),
+            end: after.is_none(),
+            start_line: before.is_none_or(|b| b == b'\n'),
+            end_line: after.is_none_or(|b| b == b'\n'),
+            word_boundary: is_word(before) != is_word(after),
+        }
+    }
 }
 
-// A list of state handles of length n.
+// A list of state handles of length n. Each state is
+// also paired with the offset in the haystack at which
+// the thread that reached it started.
+//
+// The order of a list matters to find and captures: the
+// threads in it are always in order of priority, from most
+// preferred to least. That holds because
+//
+// * add_state_to_next follows out1 before out2, and out1 is
+//   the preferred arrow of every split: the first alternative
+//   of `|`, and another round of a greedy `*`, `+` or `?`,
+// * step visits clist in order, so the threads a thread leads
+//   to in nlist come before those of any thread after it,
+// * a thread that reaches a state already in nlist is dropped,
+//   which is right since the one that got there first is
+//   preferred and their futures are the same,
+// * and a thread starting at a new offset is added last.
+//
+// So the first thread to reach the match state is the
+// leftmost-first match, the one a backtracking engine like
+// Perl's would find.
 struct List {
     s: Box<[StateID]>,
+    starts: Box<[usize]>,
     n: usize,
+    // the fewest bytes any thread in the list has to consume to
+    // reach a match (see NFA::min_remaining), or u32::MAX if
+    // none of them can; dropping threads from the end may leave
+    // this lower than it needs to be, but never higher
+    min_remaining: u32,
 }
 
-impl Matcher {
-    // create a matcher for the given NFA
-    fn new(nfa: NFA) -> Matcher {
-        let list = vec![0; nfa.states.len()].into_boxed_slice();
-        let clist = List { s: list.clone(), n: 0 };
-        let nlist = List { s: list, n: 0 };
-        let last_list_id = vec![0; nfa.states.len()].into_boxed_slice();
-        Matcher { nfa, clist, nlist, last_list_id, list_id: 0 }
+impl List {
+    // remove every state from the list
+    fn clear(&mut self) {
+        self.n = 0;
+        self.min_remaining = u32::MAX;
     }
+}
 
-    // return true if the haystack matches
-    fn is_match(&mut self, haystack: &[u8]) -> bool {
-        self.start();
-        for &byte in haystack.iter() {
-            self.step(byte);
-            std::mem::swap(&mut self.clist, &mut self.nlist);
+// A list of threads for the Pike VM in Matcher::captures. Like
+// a List, but each thread also has its own capture slots.
+struct Threads {
+    // the number of slots each thread has
+    nslots: usize,
+    // the state each thread is in
+    s: Vec<StateID>,
+    // the slots of every thread, one after the other
+    slots: Vec<Option<usize>>,
+    // what add has yet to do
+    stack: Vec<Frame>,
+}
+
+// A step that Threads::add has yet to take.
+enum Frame {
+    // visit the given state
+    Explore(StateID),
+    // put an old value back in a slot
+    Restore { slot: usize, value: Option<usize> },
+}
+
+impl Threads {
+    // create an empty list of threads with the given number of slots
+    fn new(nslots: usize) -> Threads {
+        Threads { nslots, s: vec![], slots: vec![], stack: vec![] }
+    }
+
+    // the slots of the i'th thread
+    fn slots(&self, i: usize) -> &[Option<usize>] {
+        &self.slots[i * self.nslots..][..self.nslots]
+    }
+
+    // remove every thread
+    fn clear(&mut self) {
+        self.truncate(0);
+    }
+
+    // keep only the first n threads
+    fn truncate(&mut self, n: usize) {
+        self.s.truncate(n);
+        self.slots.truncate(n * self.nslots);
+    }
+
+    // keep only the threads whose slots satisfy keep, in order
+    fn retain(&mut self, mut keep: impl FnMut(&[Option<usize>]) -> bool) {
+        let mut n = 0;
+        for i in 0..self.s.len() {
+            if keep(self.slots(i)) {
+                self.s[n] = self.s[i];
+                self.slots.copy_within(
+                    i * self.nslots..(i + 1) * self.nslots,
+                    n * self.nslots,
+                );
+                n += 1;
+            }
         }
-        self.clist.s[..self.clist.n]
-            .iter()
-            .any(|&sid| matches!(self.nfa.states[sid as usize], State::Match))
+        self.truncate(n);
+    }
+
+    // Add a thread in the given state, with the given slots, to
+    // the end of the list. This follows unlabeled arrows like
+    // add_state_to_next, and seen plays the part of the list
+    // IDs. A Save state records `at` in its slot for the states
+    // after it, and then puts the old value back for whatever is
+    // added next. Like add_state_to_next, this uses a stack of
+    // its own instead of recursing, where putting a slot back
+    // waits on the stack below the states that come after it.
+    fn add(
+        &mut self,
+        nfa: &NFA,
+        seen: &mut [bool],
+        sid: StateID,
+        look: Look,
+        at: usize,
+        slots: &mut [Option<usize>],
+    ) {
+        let min_remaining = nfa.min_remaining();
+        self.stack.push(Frame::Explore(sid));
+        while let Some(frame) = self.stack.pop() {
+            let sid = match frame {
+                Frame::Explore(sid) => sid,
+                Frame::Restore { slot, value } => {
+                    slots[slot] = value;
+                    continue;
+                }
+            };
+            if std::mem::replace(&mut seen[sid as usize], true) {
+                continue;
+            }
+            match nfa.states[sid as usize] {
+                State::Split { out1, out2 } => {
+                    self.stack.push(Frame::Explore(out2));
+                    self.stack.push(Frame::Explore(out1));
+                }
+                State::Save { slot, out } => {
+                    let value = slots[slot].replace(at);
+                    self.stack.push(Frame::Restore { slot, value });
+                    self.stack.push(Frame::Explore(out));
+                }
+                State::StartText { out } if look.start => {
+                    self.stack.push(Frame::Explore(out));
+                }
+                State::EndText { out } if look.end => {
+                    self.stack.push(Frame::Explore(out));
+                }
+                State::StartLine { out } if look.start_line => {
+                    self.stack.push(Frame::Explore(out));
+                }
+                State::EndLine { out } if look.end_line => {
+                    self.stack.push(Frame::Explore(out));
+                }
+                State::WordBoundary { negate, out }
+                    if look.word_boundary != negate =>
+                {
+                    self.stack.push(Frame::Explore(out));
+                }
+                State::StartText { .. }
+                | State::EndText { .. }
+                | State::WordBoundary { .. } => {}
+                // like in add_state_to_next, a dead thread is dropped
+                _ if min_remaining[sid as usize] == u32::MAX => {}
+                _ => {
+                    self.s.push(sid);
+                    self.slots.extend_from_slice(slots);
+                }
+            }
+        }
+    }
+}
+
+// The unlabeled arrows followed from a set of threads for
+// Matcher::captures_posix. Each state keeps the best slots of any
+// thread that reached it, by posix_better. When a state gets
+// better slots after its arrows were followed, they're followed
+// again, so the states after it get better slots too.
+//
+// This stops because a state's slots only ever get better, and a
+// state can only have so many different slots at one offset. But
+// following the same arrows more than once makes this costlier
+// than Threads::add.
+struct Closure {
+    // the number of slots each state has
+    nslots: usize,
+    // the best slots of each state reached, one after the other
+    slots: Vec<Option<usize>>,
+    // whether each state has been reached
+    reached: Vec<bool>,
+    // the states reached, in the order they were first reached
+    order: Vec<StateID>,
+    // the states whose arrows have yet to be followed
+    stack: Vec<StateID>,
+    // the slots of the state being followed
+    current: Vec<Option<usize>>,
+}
+
+impl Closure {
+    // create an empty closure over the given number of states
+    fn new(nstates: usize, nslots: usize) -> Closure {
+        Closure {
+            nslots,
+            slots: vec![None; nstates * nslots],
+            reached: vec![false; nstates],
+            order: vec![],
+            stack: vec![],
+            current: vec![None; nslots],
+        }
+    }
+
+    // Reach the given state with the given slots, unless it's
+    // been reached with better ones already.
+    fn reach(&mut self, sid: StateID, slots: &[Option<usize>]) {
+        let sid_slots =
+            &mut self.slots[sid as usize * self.nslots..][..self.nslots];
+        if self.reached[sid as usize] {
+            if !posix_better(slots, sid_slots) {
+                return;
+            }
+        } else {
+            self.reached[sid as usize] = true;
+            self.order.push(sid);
+        }
+        sid_slots.copy_from_slice(slots);
+        self.stack.push(sid);
+    }
+
+    // Follow unlabeled arrows from the states reached until
+    // every state has the best slots it can get, where look says
+    // which anchors hold and a Save state records `at`.
+    fn follow(&mut self, nfa: &NFA, look: Look, at: usize) {
+        while let Some(sid) = self.stack.pop() {
+            let mut current = std::mem::take(&mut self.current);
+            current.copy_from_slice(
+                &self.slots[sid as usize * self.nslots..][..self.nslots],
+            );
+            match nfa.states[sid as usize] {
+                State::Split { out1, out2 } => {
+                    self.reach(out1, &current);
+                    self.reach(out2, &current);
+                }
+                State::Save { slot, out } => {
+                    current[slot] = Some(at);
+                    self.reach(out, &current);
+                }
+                State::StartText { out } if look.start => {
+                    self.reach(out, &current);
+                }
+                State::EndText { out } if look.end => {
+                    self.reach(out, &current);
+                }
+                State::StartLine { out } if look.start_line => {
+                    self.reach(out, &current);
+                }
+                State::EndLine { out } if look.end_line => {
+                    self.reach(out, &current);
+                }
+                State::WordBoundary { negate, out }
+                    if look.word_boundary != negate =>
+                {
+                    self.reach(out, &current);
+                }
+                _ => {}
+            }
+            self.current = current;
+        }
+    }
+
+    // Add a thread to the list for each state reached that reads
+    // a byte or matches, and forget every state reached.
+    fn finish(&mut self, nfa: &NFA, list: &mut Threads) {
+        for &sid in self.order.iter() {
+            self.reached[sid as usize] = false;
+            match nfa.states[sid as usize] {
+                State::Split { .. }
+                | State::Save { .. }
+                | State::StartText { .. }
+                | State::EndText { .. }
+                | State::StartLine { .. }
+                | State::EndLine { .. }
+                | State::WordBoundary { .. } => {}
+                _ => {
+                    list.s.push(sid);
+                    list.slots.extend_from_slice(
+                        &self.slots[sid as usize * self.nslots..]
+                            [..self.nslots],
+                    );
+                }
+            }
+        }
+        self.order.clear();
+    }
+}
+
+// Return true if slots a are better than slots b by the POSIX
+// rule in Matcher::captures_posix: the first group that differs
+// decides, and for that group, starting earlier is better, and
+// then ending later. A group that has started, or ended, is
+// better than one that hasn't, so `a|(a)` matching `a` sets
+// group 1.
+//
+// Group 0 only ever differs in where it starts, since its end is
+// only set once a match is found.
+fn posix_better(a: &[Option<usize>], b: &[Option<usize>]) -> bool {
+    use std::cmp::{Ordering, Reverse};
+
+    for (a, b) in a.chunks(2).zip(b.chunks(2)) {
+        // None is less than Some, so flip the starts around
+        // before comparing them, but leave None as the worst
+        let start = |s: Option<usize>| s.map(Reverse);
+        let order =
+            start(a[0]).cmp(&start(b[0])).then_with(|| a[1].cmp(&b[1]));
+        match order {
+            Ordering::Greater => return true,
+            Ordering::Less => return false,
+            Ordering::Equal => {}
+        }
+    }
+    false
+}
+
+impl Scratch {
+    // Create scratch space sized for the given NFA, or return an error
+    // if there isn't enough memory for it. Using `vec![0; n]` here
+    // would abort the process instead, and these buffers are the only
+    // ones whose size depends on the pattern.
+    fn try_new(nfa: &NFA) -> Result<Scratch, TryReserveError> {
+        fn zeroed<T: Clone + Default>(
+            len: usize,
+        ) -> Result<Box<[T]>, TryReserveError> {
+            let mut v = Vec::new();
+            v.try_reserve_exact(len)?;
+            v.resize(len, T::default());
+            Ok(v.into_boxed_slice())
+        }
+
+        let len = nfa.states.len();
+        let list = || -> Result<List, TryReserveError> {
+            let (s, starts) = (zeroed(len)?, zeroed(len)?);
+            Ok(List { s, starts, n: 0, min_remaining: u32::MAX })
+        };
+        let (clist, nlist) = (list()?, list()?);
+        let last_list_id = zeroed(len)?;
+        let stack = vec![];
+        Ok(Scratch { clist, nlist, last_list_id, list_id: 0, stack })
+    }
+
+    // Forget the lists of any previous search. The list ID is
+    // bumped so that last_list_id doesn't claim any state is
+    // already on the next list.
+    fn reset(&mut self) {
+        self.clist.clear();
+        self.nlist.clear();
+        self.increment_list_id();
+    }
+
+    // the number of states this scratch space has room for
+    fn capacity(&self) -> usize {
+        self.last_list_id.len()
+    }
+
+    // Nothing stops a caller from using scratch space sized for
+    // one NFA with another, bigger NFA. This makes room for the
+    // given NFA if there isn't enough already, or returns an
+    // error if there isn't enough memory for it. (Scratch space
+    // that is too big is fine.)
+    fn grow(&mut self, nfa: &NFA) -> Result<(), TryReserveError> {
+        if self.capacity() < nfa.states.len() {
+            *self = Scratch::try_new(nfa)?;
+        }
+        Ok(())
     }
 
-    // add starting states to clist
-    fn start(&mut self) {
+    // add starting states to clist, for a search
+    // that begins at the given offset in the haystack
+    fn start(&mut self, nfa: &NFA, haystack: &[u8], at: usize) {
+        // Callers either made this scratch space for the NFA or
+        // grew it for the NFA first.
+        debug_assert!(
+            self.capacity() >= nfa.states.len(),
+            "scratch space is too small for this NFA",
+        );
         self.increment_list_id();
         // we add the states to nlist first, since
         // that's what add_state_to_next does, and
         // then just swap the lists
-        self.nlist.n = 0;
-        self.add_state_to_next(self.nfa.start);
+        self.nlist.clear();
+        self.add_state_to_next(nfa, nfa.start, Look::at(haystack, at), at);
         std::mem::swap(&mut self.clist, &mut self.nlist);
     }
 
-    // step over all states in clist and add matching states to nlist
-    fn step(&mut self, haystack_byte: u8) {
+    // step over all states in clist and add matching states to nlist,
+    // where look says which anchors hold just after haystack_byte
+    fn step(&mut self, nfa: &NFA, haystack_byte: u8, look: Look) {
         self.increment_list_id();
-        self.nlist.n = 0;
+        self.nlist.clear();
         // This is a good example of how borrowck can inhibit composition. We
         // would ideally want to use `self.clist.s[..self.clist.n].iter()` here
         // and iterate over the state handles directly. Instead, we iterate
@@ -399,37 +4010,108 @@ impl Matcher {
         //
         // Now, `self.add_state_to_next` doesn't actually need mutable access
         // to `clist`, so there is no actual conflict here. But borrowck can't
-        // see past function boundaries. We could break down our `Matcher`
+        // see past function boundaries. We could break down our `Scratch`
         // type into smaller components, but that's pretty heavy-handed here
         // and likely awkward. We could also use interior mutability (e.g.,
         // RefCell) in places to avoid needing to borrow `self` mutably. Or we
         // could just iterate over the indices of the list like we do below.
         // The other work-arounds may be appropriate in other circumstances!
         for i in 0..self.clist.n {
-            let sid = self.clist.s[i];
-            match self.nfa.states[sid as usize] {
-                State::Literal { byte, out } if byte == haystack_byte => {
-                    self.add_state_to_next(out);
-                }
-                _ => {}
+            let (sid, start) = (self.clist.s[i], self.clist.starts[i]);
+            if let Some(out) = nfa.states[sid as usize].next(haystack_byte) {
+                self.add_state_to_next(nfa, out, look, start);
             }
         }
     }
 
-    // add given state handle to the nlist
-    fn add_state_to_next(&mut self, sid: StateID) {
-        if self.list_id == self.last_list_id[sid as usize] {
-            return;
+    // return true if clist contains the match state
+    fn has_match(&self, nfa: &NFA) -> bool {
+        self.clist.s[..self.clist.n]
+            .iter()
+            .any(|&sid| matches!(nfa.states[sid as usize], State::Match))
+    }
+
+    // add given state handle to the nlist, where look says which
+    // anchors hold at the nlist's position and start is where the
+    // thread adding it started (see List for why the order in
+    // which states are added here matters)
+    fn add_state_to_next(
+        &mut self,
+        nfa: &NFA,
+        sid: StateID,
+        look: Look,
+        start: usize,
+    ) {
+        // The original implementation recurses on each unlabeled
+        // arrow, so a long enough chain of them overflows the call
+        // stack. Instead, states still to visit go on a stack of
+        // our own. Pushing out2 before out1 means states are added
+        // in the same order the recursion would add them in.
+        let min_remaining = nfa.min_remaining();
+        self.stack.push(sid);
+        while let Some(sid) = self.stack.pop() {
+            if self.list_id == self.last_list_id[sid as usize] {
+                continue;
+            }
+            self.last_list_id[sid as usize] = self.list_id;
+            match nfa.states[sid as usize] {
+                State::Split { out1, out2 } => {
+                    // follow unlabeled arrows
+                    self.stack.push(out2);
+                    self.stack.push(out1);
+                }
+                // only captures needs the slots
+                State::Save { out, .. } => self.stack.push(out),
+                // follow anchors that hold, and drop the rest
+                State::StartText { out } => {
+                    if look.start {
+                        self.stack.push(out);
+                    }
+                }
+                State::EndText { out } => {
+                    if look.end {
+                        self.stack.push(out);
+                    }
+                }
+                State::StartLine { out } => {
+                    if look.start_line {
+                        self.stack.push(out);
+                    }
+                }
+                State::EndLine { out } => {
+                    if look.end_line {
+                        self.stack.push(out);
+                    }
+                }
+                State::WordBoundary { negate, out } => {
+                    if look.word_boundary != negate {
+                        self.stack.push(out);
+                    }
+                }
+                // a thread that can't reach a match is dead already
+                _ if min_remaining[sid as usize] == u32::MAX => {}
+                _ => {
+                    self.nlist.s[self.nlist.n] = sid;
+                    self.nlist.starts[self.nlist.n] = start;
+                    self.nlist.n += 1;
+                    self.nlist.min_remaining = self
+                        .nlist
+                        .min_remaining
+                        .min(min_remaining[sid as usize]);
+                }
+            }
         }
-        self.last_list_id[sid as usize] = self.list_id;
-        if let State::Split { out1, out2 } = self.nfa.states[sid as usize] {
-            // follow unlabeled arrows
-            self.add_state_to_next(out1);
-            self.add_state_to_next(out2);
-            return;
+    }
+
+    // Start a new list ID for nlist, with just the states on it
+    // marked as being there. After threads are dropped from the
+    // end of nlist, this lets the states they were in, and the
+    // unlabeled arrows that led to them, be added again.
+    fn forget_dropped(&mut self) {
+        self.increment_list_id();
+        for &sid in self.nlist.s[..self.nlist.n].iter() {
+            self.last_list_id[sid as usize] = self.list_id;
         }
-        self.nlist.s[self.nlist.n] = sid;
-        self.nlist.n += 1;
     }
 
     // increment to a new list id
@@ -440,49 +4122,690 @@ impl Matcher {
         // instead, but it's not hard to make this
         // correct. On overflow, we reset everything
         // back to the starting condition.
-        self.list_id = match self.list_id.checked_add(1) {
-            Some(list_id) => list_id,
-            None => {
-                for last_list_id in self.last_list_id.iter_mut() {
-                    *last_list_id = 0;
-                }
-                1
-            }
-        };
+        if self.list_id == u32::MAX {
+            self.reset_generations();
+        }
+        self.list_id += 1;
+    }
+
+    // Go back to the first list ID, with no state on any list.
+    // Only the list being filled needs its states marked in
+    // last_list_id, so this is safe whenever no list is.
+    fn reset_generations(&mut self) {
+        self.last_list_id.fill(0);
+        self.list_id = 0;
     }
 }
 
 fn main() -> std::process::ExitCode {
     use std::process::ExitCode;
 
-    let mut argv = std::env::args_os();
-    if argv.len() < 3 {
-        eprintln!("usage: nfa regexp string...");
-        return ExitCode::FAILURE;
+    let usage = || {
+        eprintln!(
+            "usage: nfa [--fixed-strings] [--unanchored] [--ignore-case] \
+             [--case-mismatch] [--failure-offset] [--count-steps] [--find] \
+             [--only-matching] [--find-all] [--find-overlapping] \
+             [--count-matches] [--count-matches-naive] \
+             [--split] [--rsplit] [--replace STRING] [--limit N] \
+             [--files] [--lines] [--feed] [--captures] [--captures-len] \
+             [--no-pool] [--longest] [--reverse] [--utf8] \
+             [--reset-generations] \
+             [--intersect REGEXP] [--disjoint REGEXP] \
+             [--estimate-states] [--threads N] [--lenient] [--postfix] \
+             [--multi-line] [--no-dot-newline] \
+             [--shortest-match] [--prefix] [--dump-nfa] [--first-bytes] \
+             [--max-pattern-len N] [--max-paren-depth N] [--max-states N] \
+             [--pattern-file PATH | regexp] string..."
+        );
+        ExitCode::FAILURE
+    };
+
+    // Flags come before the pattern. A '--' ends the flags,
+    // in case the pattern itself starts with '--'.
+    let mut argv = std::env::args_os().skip(1).peekable();
+    let mut fixed_strings = false;
+    let mut anchoring = Anchoring::default();
+    let mut ignore_case = false;
+    let mut failure_offset = false;
+    let mut count_steps = false;
+    let mut find = false;
+    let mut find_all = false;
+    let mut only_matching = false;
+    // the most replacements --replace makes,
+    // or the most pieces --rsplit splits into
+    let mut limit = None;
+    let mut find_overlapping = false;
+    let mut shortest_match = false;
+    let mut count_matches = false;
+    let mut naive_count = false;
+    let mut dump_nfa = false;
+    let mut first_bytes = false;
+    let mut split = false;
+    let mut rsplit = false;
+    let mut files = false;
+    let mut lines = false;
+    let mut feed = false;
+    let mut captures = false;
+    let mut captures_len = false;
+    let mut no_pool = false;
+    let mut reset_generations = false;
+    let mut intersect = None;
+    let mut disjoint = None;
+    let mut postfix = false;
+    let mut estimate = false;
+    let mut threads = None;
+    let mut kind = MatchKind::default();
+    let mut reverse = false;
+    let mut utf8 = false;
+    let mut lenient = false;
+    let mut case_mismatch = false;
+    let mut multi_line = false;
+    let mut dot_matches_newline = true;
+    let mut replace = None;
+    let mut pattern_file = None;
+    let mut limits = Limits::default();
+    while let Some(flag) =
+        argv.next_if(|arg| arg.to_string_lossy().starts_with("--"))
+    {
+        match &*flag.to_string_lossy() {
+            "--" => break,
+            "--fixed-strings" => fixed_strings = true,
+            "--unanchored" => anchoring = Anchoring::Unanchored,
+            "--ignore-case" => ignore_case = true,
+            "--case-mismatch" => case_mismatch = true,
+            "--failure-offset" => failure_offset = true,
+            "--count-steps" => count_steps = true,
+            "--find" => find = true,
+            "--only-matching" => only_matching = true,
+            "--find-all" => find_all = true,
+            "--find-overlapping" => find_overlapping = true,
+            "--shortest-match" => shortest_match = true,
+            "--prefix" => anchoring = Anchoring::Start,
+            "--count-matches" => count_matches = true,
+            // Count the way find_iter does, for comparison.
+            "--count-matches-naive" => {
+                (count_matches, naive_count) = (true, true)
+            }
+            "--dump-nfa" => dump_nfa = true,
+            "--first-bytes" => first_bytes = true,
+            flag @ ("--max-pattern-len" | "--max-paren-depth"
+            | "--max-states" | "--limit" | "--threads") => {
+                let Some(n) =
+                    argv.next().and_then(|n| n.to_str()?.parse().ok())
+                else {
+                    return usage();
+                };
+                match flag {
+                    "--max-pattern-len" => limits.max_pattern_len = n,
+                    "--max-paren-depth" => limits.max_paren_depth = n,
+                    "--max-states" => limits.max_states = n,
+                    "--threads" => threads = Some(n),
+                    _ => limit = Some(n),
+                }
+            }
+            "--split" => split = true,
+            "--rsplit" => rsplit = true,
+            "--files" => files = true,
+            "--lines" => lines = true,
+            "--feed" => feed = true,
+            "--captures" => captures = true,
+            "--captures-len" => captures_len = true,
+            // Allocate new lists for every search, for comparison.
+            "--no-pool" => no_pool = true,
+            "--reset-generations" => reset_generations = true,
+            "--estimate-states" => estimate = true,
+            "--longest" => kind = MatchKind::Longest,
+            "--reverse" => reverse = true,
+            "--utf8" => utf8 = true,
+            "--lenient" => lenient = true,
+            "--multi-line" => multi_line = true,
+            "--no-dot-newline" => dot_matches_newline = false,
+            "--postfix" => postfix = true,
+            "--replace" => {
+                let Some(replacement) = argv.next() else { return usage() };
+                replace = Some(arg_to_bytes(replacement));
+            }
+            "--intersect" => {
+                let Some(other) = argv.next() else { return usage() };
+                intersect = Some(arg_to_bytes(other));
+            }
+            "--disjoint" => {
+                let Some(other) = argv.next() else { return usage() };
+                disjoint = Some(arg_to_bytes(other));
+            }
+            "--pattern-file" => {
+                let Some(path) = argv.next() else { return usage() };
+                pattern_file = Some(std::path::PathBuf::from(path));
+            }
+            flag => {
+                eprintln!("unrecognized flag {flag}");
+                return usage();
+            }
+        }
+    }
+
+    // Long or awkward patterns can be read from a file
+    // instead. A single trailing newline is dropped since
+    // most editors add one.
+    let pattern = if let Some(path) = pattern_file {
+        let mut pattern = match std::fs::read(&path) {
+            Ok(pattern) => pattern,
+            Err(err) => {
+                eprintln!(
+                    "failed to read pattern file {}: {err}",
+                    path.display()
+                );
+                return ExitCode::FAILURE;
+            }
+        };
+        if pattern.last() == Some(&b'\n') {
+            pattern.pop();
+        }
+        pattern
+    } else {
+        let Some(arg) = argv.next() else { return usage() };
+        arg_to_bytes(arg)
+    };
+    // Haystacks are optional when all we
+    // want to do is look at the NFA.
+    if argv.peek().is_none()
+        && !dump_nfa
+        && !captures_len
+        && !estimate
+        && !first_bytes
+        && disjoint.is_none()
+    {
+        return usage();
     }
 
-    let Ok(pattern) = argv.by_ref().skip(1).next().unwrap().into_string()
-    else {
-        eprintln!("pattern is invalid UTF-8");
-        return ExitCode::FAILURE;
+    // Patterns given with --postfix skip re2post, and can't
+    // have flags. Otherwise, a leading `(?i)` folds case for
+    // just the pattern it starts. --ignore-case is left for
+    // building instead, so that the same parsed pattern can
+    // be built both ways.
+    let flags = Flags { ignore_case: false, multi_line, dot_matches_newline };
+    let parse = |pattern: &[u8]| {
+        if postfix {
+            return parse_postfix(pattern, &limits).map(|postfix| {
+                ParsedPattern { postfix, ngroups: 0, ignore_case: false }
+            });
+        }
+        let (skip, flags) = inline_flags(pattern, flags)?;
+        let (postfix, ngroups) =
+            re2post(&pattern[skip..], &limits, utf8, lenient, flags).map_err(
+                |err| ParseError { offset: err.offset + skip, ..err },
+            )?;
+        Ok(ParsedPattern { postfix, ngroups, ignore_case: flags.ignore_case })
+    };
+    let options =
+        BuildOptions { captures: captures || captures_len, ignore_case };
+    // With --case-mismatch, the pattern is also built to ignore
+    // case, to find the haystacks that only match that way.
+    let (nfa, folded) = if fixed_strings {
+        // Like grep, each line of the pattern
+        // is a separate literal to search for.
+        let literals: Vec<&[u8]> = pattern.split(|&b| b == b'\n').collect();
+        match NFA::from_literals(&literals, &limits) {
+            Ok(nfa) => {
+                let folded = case_mismatch.then(|| {
+                    NFA::from_literals(&literals, &limits)
+                        .map(|nfa| nfa.ignore_case())
+                });
+                let nfa = if ignore_case { nfa.ignore_case() } else { nfa };
+                (Ok(nfa), folded)
+            }
+            Err(err) => {
+                eprintln!("error building NFA: {err}");
+                return ExitCode::FAILURE;
+            }
+        }
+    } else {
+        let parsed = match parse(&pattern) {
+            Ok(parsed) => parsed,
+            Err(err) => {
+                let shown = String::from_utf8_lossy(&pattern);
+                eprintln!("bad regexp {shown}");
+                // re2post stops at the first problem, but
+                // it's nicer to see them all at once.
+                // The flag group is checked before anything
+                // else, so there's nothing more to find if it's
+                // the problem.
+                let mut errs = match inline_flags(&pattern, flags) {
+                    Ok((skip, _)) if !postfix => {
+                        diagnose(&pattern[skip..], &limits, lenient)
+                            .into_iter()
+                            .map(|err| ParseError {
+                                offset: err.offset + skip,
+                                ..err
+                            })
+                            .collect()
+                    }
+                    _ => vec![],
+                };
+                if errs.is_empty() {
+                    errs.push(err);
+                }
+                for err in errs {
+                    // The caret goes under the offending byte,
+                    // counting in characters since that's how
+                    // the pattern is shown.
+                    let column =
+                        String::from_utf8_lossy(&pattern[..err.offset])
+                            .chars()
+                            .count();
+                    eprintln!("  {err}");
+                    eprintln!("    {shown}");
+                    eprintln!("    {}^", " ".repeat(column));
+                }
+                return ExitCode::FAILURE;
+            }
+        };
+        // This comes before anything that could be too big.
+        if estimate {
+            let (post, _) = parsed.postfix(options);
+            println!("estimated states: {}", estimate_states(&post));
+        }
+        let folded = case_mismatch.then(|| {
+            parsed.compile(
+                BuildOptions { ignore_case: true, ..options },
+                &limits,
+            )
+        });
+        (parsed.compile(options, &limits), folded)
     };
-    let Some(post) = re2post(pattern.as_bytes()) else {
-        eprintln!("bad regexp {pattern}");
-        return ExitCode::FAILURE;
+    let report = |err: BuildError| {
+        let shown = String::from_utf8_lossy(&pattern);
+        match err {
+            BuildError::RepetitionsTooBig => {
+                eprintln!("bad regexp {shown}");
+                eprintln!("  {err}");
+            }
+            _ => eprintln!("error in post2nfa {shown}: {err}"),
+        }
     };
-    let Some(nfa) = NFA::post2nfa(&post) else {
-        eprintln!("error in post2nfa {pattern}");
-        return ExitCode::FAILURE;
+    let nfa = match nfa {
+        Ok(nfa) => nfa,
+        Err(err) => {
+            report(err);
+            return ExitCode::FAILURE;
+        }
     };
-    let mut matcher = Matcher::new(nfa);
-    for arg in argv {
-        let Ok(haystack) = arg.into_string() else {
-            eprintln!("haystack is invalid UTF-8");
+    let folded = match folded.transpose() {
+        Ok(folded) => folded,
+        Err(err) => {
+            report(err);
+            return ExitCode::FAILURE;
+        }
+    };
+    // Build the NFA for the pattern given to --intersect or
+    // --disjoint, the same way as the one for the main pattern.
+    let build_other = |other: &[u8]| {
+        let options = BuildOptions { captures: false, ..options };
+        let built =
+            parse(other).map_err(|err| err.to_string()).and_then(|parsed| {
+                parsed.compile(options, &limits).map_err(|err| err.to_string())
+            });
+        match built {
+            Ok(nfa) => Some(nfa),
+            Err(err) => {
+                eprintln!("bad regexp {}", String::from_utf8_lossy(other));
+                eprintln!("  {err}");
+                None
+            }
+        }
+    };
+    // Only match what the other pattern matches too.
+    let nfa = if let Some(other) = intersect {
+        let Some(other) = build_other(&other) else {
+            return ExitCode::FAILURE;
+        };
+        match nfa.intersect(&other) {
+            Ok(nfa) => nfa,
+            Err(err) => {
+                eprintln!("error building NFA: {err}");
+                return ExitCode::FAILURE;
+            }
+        }
+    } else {
+        nfa
+    };
+    // Say whether any string matches both patterns.
+    if let Some(other) = disjoint {
+        let Some(other) = build_other(&other) else {
+            return ExitCode::FAILURE;
+        };
+        match nfa.is_disjoint(&other) {
+            Ok(true) => println!("disjoint"),
+            Ok(false) => println!("not disjoint"),
+            Err(err) => {
+                eprintln!("error building NFA: {err}");
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+    // The haystacks are expected to be reversed already.
+    let nfa = if reverse {
+        match nfa.reverse() {
+            Ok(nfa) => nfa,
+            Err(err) => {
+                eprintln!("error building NFA: {err}");
+                return ExitCode::FAILURE;
+            }
+        }
+    } else {
+        nfa
+    };
+    if dump_nfa {
+        print!("{}", nfa.dump_with_closures());
+    }
+    if estimate {
+        println!("states: {}", nfa.states.len());
+    }
+    if first_bytes {
+        println!("first bytes: [{}]", nfa.first_byte_set().describe());
+    }
+    // Search the haystacks on N threads at once, sharing one pool
+    // of scratch space. This only does plain matching, like with
+    // no other flags, and prints the matches in order.
+    if let Some(threads) = threads {
+        let haystacks: Vec<Vec<u8>> = argv.map(arg_to_bytes).collect();
+        let pool = MatcherPool::new(Arc::new(nfa));
+        let chunk_len = haystacks.len().div_ceil(threads.max(1)).max(1);
+        let results: Vec<Result<bool, TryReserveError>> =
+            std::thread::scope(|s| {
+                let searches: Vec<_> = haystacks
+                    .chunks(chunk_len)
+                    .map(|chunk| {
+                        let pool = &pool;
+                        s.spawn(move || {
+                            chunk
+                                .iter()
+                                .map(|haystack| pool.is_match(haystack))
+                                .collect::<Vec<_>>()
+                        })
+                    })
+                    .collect();
+                searches
+                    .into_iter()
+                    .flat_map(|search| {
+                        search.join().unwrap_or_else(|err| {
+                            std::panic::resume_unwind(err)
+                        })
+                    })
+                    .collect()
+            });
+        let mut stdout = std::io::stdout().lock();
+        for (haystack, matched) in haystacks.iter().zip(results) {
+            match matched {
+                Ok(false) => {}
+                Ok(true) => {
+                    if writeln_bytes(&mut stdout, haystack).is_err() {
+                        return ExitCode::FAILURE;
+                    }
+                }
+                Err(err) => {
+                    eprintln!("failed to allocate scratch space: {err}");
+                    return ExitCode::FAILURE;
+                }
+            }
+        }
+        return ExitCode::SUCCESS;
+    }
+    let mut matcher = match Matcher::try_new(nfa) {
+        Ok(matcher) => matcher,
+        Err(err) => {
+            eprintln!("failed to allocate matcher: {err}");
+            return ExitCode::FAILURE;
+        }
+    };
+    matcher.kind = kind;
+    matcher.anchoring = anchoring;
+    let mut folded = match folded.map(Matcher::try_new).transpose() {
+        Ok(folded) => folded,
+        Err(err) => {
+            eprintln!("failed to allocate matcher: {err}");
+            return ExitCode::FAILURE;
+        }
+    };
+    if let Some(ref mut folded) = folded {
+        folded.anchoring = anchoring;
+    }
+    // Debug builds can start the list IDs just short of running
+    // out, so that the tests can check what happens when they do.
+    #[cfg(debug_assertions)]
+    if let Some(id) = std::env::var_os("NFA_LIST_ID") {
+        let Some(id) = id.to_str().and_then(|id| id.parse().ok()) else {
+            eprintln!("NFA_LIST_ID is not a valid list ID");
             return ExitCode::FAILURE;
         };
-        if matcher.is_match(haystack.as_bytes()) {
-            println!("{haystack}");
+        matcher.scratch.list_id = id;
+    }
+    if captures_len {
+        println!("groups: {}", matcher.captures_len());
+    }
+    let mut stdout = std::io::stdout().lock();
+    for arg in argv {
+        if reset_generations && matcher.reset_pending() {
+            matcher.reset_generations();
+            eprintln!("reset generations");
+        }
+        let mut haystack = arg_to_bytes(arg.clone());
+        let matched = if failure_offset {
+            match matcher.match_or_failure_offset(&haystack) {
+                Ok(()) => true,
+                Err(at) => {
+                    let haystack = String::from_utf8_lossy(&haystack);
+                    eprintln!("{haystack}: failed at offset {at}");
+                    false
+                }
+            }
+        } else if only_matching && !find_all {
+            // print the leftmost match instead of the haystack
+            match matcher.find_bytes(&haystack) {
+                Some(bytes) => {
+                    haystack = bytes.to_vec();
+                    true
+                }
+                None => false,
+            }
+        } else if find {
+            // this searches anywhere in the haystack
+            match matcher.find(&haystack) {
+                Some((start, end)) => {
+                    let haystack = String::from_utf8_lossy(&haystack);
+                    eprintln!("{haystack}: match at {start}..{end}");
+                    true
+                }
+                None => false,
+            }
+        } else if find_all {
+            let shown = String::from_utf8_lossy(&haystack).into_owned();
+            let mut found = vec![];
+            for m in matcher.find_iter_full(&haystack) {
+                eprintln!("{shown}: match at {}..{}", m.start, m.end);
+                found.push(m.bytes);
+            }
+            let matched = !found.is_empty();
+            // print each match on its own line instead of the haystack
+            if only_matching {
+                haystack = found.join(&b'\n');
+            }
+            matched
+        } else if find_overlapping {
+            let spans = matcher.find_overlapping(&haystack);
+            let shown = String::from_utf8_lossy(&haystack);
+            for &(start, end) in &spans {
+                eprintln!("{shown}: match at {start}..{end}");
+            }
+            !spans.is_empty()
+        } else if shortest_match {
+            match matcher.shortest_match(&haystack) {
+                Some(end) => {
+                    let haystack = String::from_utf8_lossy(&haystack);
+                    eprintln!("{haystack}: shortest match ends at {end}");
+                    true
+                }
+                None => false,
+            }
+        } else if count_matches {
+            let count = if naive_count {
+                matcher.find_iter(&haystack).count()
+            } else {
+                matcher.count_matches_in(&haystack)
+            };
+            let haystack = String::from_utf8_lossy(&haystack);
+            eprintln!("{haystack}: {count} matches");
+            count > 0
+        } else if lines {
+            // Each haystack names a file, and each line of it is
+            // searched on its own. The lines that match are
+            // printed instead of the file's name.
+            let path = std::path::PathBuf::from(arg);
+            let searched = std::fs::File::open(&path).and_then(|file| {
+                let mut grep = LineGrep::new(file);
+                while let Some(line) = grep.next_match(&mut matcher)? {
+                    writeln_bytes(&mut stdout, line)?;
+                }
+                Ok(())
+            });
+            if let Err(err) = searched {
+                eprintln!("failed to search {}: {err}", path.display());
+            }
+            false
+        } else if files {
+            // each haystack names a file to search
+            let path = std::path::PathBuf::from(arg);
+            match std::fs::File::open(&path)
+                .and_then(|file| matcher.is_match_reader(file))
+            {
+                Ok(matched) => matched,
+                Err(err) => {
+                    eprintln!("failed to search {}: {err}", path.display());
+                    false
+                }
+            }
+        } else if captures {
+            // show the span of each group, with `-` for one
+            // that didn't take part in the match
+            let slots = matcher.captures(&haystack);
+            if no_pool {
+                matcher.pool.clear();
+            }
+            match slots {
+                Some(slots) => {
+                    let spans: Vec<String> = slots
+                        .chunks(2)
+                        .map(|span| match *span {
+                            [Some(start), Some(end)] => {
+                                format!("{start}..{end}")
+                            }
+                            _ => "-".to_string(),
+                        })
+                        .collect();
+                    let haystack = String::from_utf8_lossy(&haystack);
+                    eprintln!("{haystack}: groups {}", spans.join(" "));
+                    true
+                }
+                None => false,
+            }
+        } else if feed {
+            // Feed the haystack one byte at a time, noting the
+            // length of each prefix of it that matches.
+            matcher.reset();
+            let mut ends = vec![];
+            for (i, &byte) in haystack.iter().enumerate() {
+                if matcher.is_match_now() {
+                    ends.push(i);
+                }
+                matcher.feed(byte);
+            }
+            let matched = matcher.is_match_now();
+            if matched {
+                ends.push(haystack.len());
+            }
+            let shown = String::from_utf8_lossy(&haystack);
+            eprintln!("{shown}: matching prefixes {ends:?}");
+            matched
+        } else if split {
+            let pieces: Vec<_> = matcher
+                .split(&haystack)
+                .into_iter()
+                .map(String::from_utf8_lossy)
+                .collect();
+            let shown = String::from_utf8_lossy(&haystack);
+            eprintln!("{shown}: {pieces:?}");
+            pieces.len() > 1
+        } else if rsplit {
+            let pieces: Vec<_> = matcher
+                .rsplitn(&haystack, limit.unwrap_or(usize::MAX))
+                .into_iter()
+                .map(String::from_utf8_lossy)
+                .collect();
+            let shown = String::from_utf8_lossy(&haystack);
+            eprintln!("{shown}: {pieces:?}");
+            pieces.len() > 1
+        } else if let Some(ref replacement) = replace {
+            // print the haystack with its matches replaced
+            let matched = matcher.find(&haystack).is_some();
+            haystack = match limit {
+                Some(n) => matcher.replacen(&haystack, n, replacement),
+                None => matcher.replace_all(&haystack, replacement),
+            };
+            matched
+        } else if count_steps {
+            let (matched, steps) = matcher.is_match_counting(&haystack);
+            let haystack = String::from_utf8_lossy(&haystack);
+            eprintln!("{haystack}: {steps} steps");
+            matched
+        } else if let Some(ref mut folded) = folded {
+            // only the haystacks that need case ignored to match
+            match (folded.is_match(&haystack), matcher.is_match(&haystack)) {
+                (Ok(folded), Ok(exact)) => folded && !exact,
+                (Err(err), _) | (_, Err(err)) => {
+                    eprintln!("failed to allocate scratch space: {err}");
+                    return ExitCode::FAILURE;
+                }
+            }
+        } else {
+            match matcher.is_match(&haystack) {
+                Ok(matched) => matched,
+                Err(err) => {
+                    eprintln!("failed to allocate scratch space: {err}");
+                    return ExitCode::FAILURE;
+                }
+            }
+        };
+        // The haystack isn't necessarily valid UTF-8,
+        // so write it back out exactly as we got it.
+        if matched && writeln_bytes(&mut stdout, &haystack).is_err() {
+            return ExitCode::FAILURE;
         }
     }
     ExitCode::SUCCESS
 }
+
+// Convert a command line argument to the bytes we search. On Unix,
+// arguments are arbitrary bytes, so we use them as is. On other
+// platforms (i.e., Windows), arguments are sequences of 16-bit code
+// units that are usually, but not always, valid UTF-16. So we
+// convert them to UTF-8, replacing anything invalid with U+FFFD.
+fn arg_to_bytes(arg: std::ffi::OsString) -> Vec<u8> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStringExt;
+        arg.into_vec()
+    }
+    #[cfg(not(unix))]
+    {
+        arg.to_string_lossy().into_owned().into_bytes()
+    }
+}
+
+// Write the given bytes followed by a line terminator.
+fn writeln_bytes(
+    mut wtr: impl std::io::Write,
+    bytes: &[u8],
+) -> std::io::Result<()> {
+    wtr.write_all(bytes)?;
+    wtr.write_all(b"\n")
+}
@@ -18,8 +18,7 @@ fn main() -> std::process::ExitCode {
         return ExitCode::FAILURE;
     }
 
-    let Ok(pattern) = argv.by_ref().skip(1).next().unwrap().into_string()
-    else {
+    let Ok(pattern) = argv.by_ref().nth(1).unwrap().into_string() else {
         eprintln!("pattern is invalid UTF-8");
         return ExitCode::FAILURE;
     };
@@ -33,7 +32,13 @@ fn main() -> std::process::ExitCode {
     // I didn't feel strongly enough to fix that. It could
     // be fixed by either building the syntax Hir manually
     // or using the lower level regex-automata anchored APIs.
-    let anchored = format!(r"^(?:{pattern})$");
+    //
+    // The '.' tests are in the common table of the test
+    // script, which every program runs, this one included.
+    // The translations match bytes, and their '.' matches
+    // any byte, including \n. So Unicode mode is disabled
+    // and '.' is made to match \n, to get the same results.
+    let anchored = format!(r"^(?s-u:{pattern})$");
     let Ok(re) = Regex::new(&anchored) else {
         eprintln!("bad regexp {pattern}");
         return ExitCode::FAILURE;
@@ -119,10 +124,10 @@ fn re2post(re: &[u8]) -> Option<Vec<u8>> {
                 }
                 dst.push(byte);
             }
-            // Not handled in the original program.
-            // Since '.' is a meta character in the
-            // postfix syntax, it can result in UB.
-            b'.' => return None,
+            // The translations all support '.', and the
+            // common test table expects every program to
+            // accept it. The postfix output is never used
+            // here, so it's treated like any other atom.
             _ => {
                 if natom > 1 {
                     natom -= 1;
@@ -9,8 +9,9 @@
 // an ownership pattern, but the graph of states created by the *mut State
 // pointers is cyclic. Since this program copies the original's logic as much
 // as possible, the graph formed by the Rc<RefCell<State>> pointers is also
-// cyclic and thus leaks memory. (Rust's Arc and Rc types are documented to
-// leak memory on cycles.)
+// cyclic. (Rust's Arc and Rc types are documented to leak memory on cycles.)
+// To avoid leaking, the NFA keeps every state in a Vec and cuts all of their
+// arrows when it's dropped.
 //
 // * We replace the raw union representation of PtrList with a more explicit
 // sum type. Rust really has no hope of capturing the technique used in the
@@ -19,20 +20,21 @@
 // when patching together NFA fragments. We forget about trying to reuse the
 // state allocation and just create our own linked list.
 //
-// If one wants to observe the memory leak in this program, you can use Miri
-// to sniff it out:
+// Miri will report any memory leaked by this program, so you can check that
+// the cycles really are broken:
 //
 //     cargo miri run -q --manifest-path safe-translation/Cargo.toml 'a+' 'a'
 //
-// Otherwise there are very few changes here. re2post is completely untouched.
-// post2nfa is also largely the same with some minor changes to support the
-// new PtrList representation described above. In fact, the structure of
-// the original program is largely preserved. We mostly only change some
-// representational details. Of course, do incur some extra costs by doing
-// things "safely" (e.g., reference counting where there was none before), but
-// it's unclear how to quantify that. Namely, these programs were never written
-// with performance in mind, and evaluating their performance would really be
-// an entirely different exercise.
+// Otherwise there are very few changes here. re2post is untouched aside from
+// supporting the '.' wildcard, which the original rejects, and backslash
+// escapes. post2nfa is also largely the same with some minor changes to
+// support the new PtrList representation described above. In fact, the
+// structure of the original program is largely preserved. We mostly only
+// change some representational details. Of course, do incur some extra costs
+// by doing things "safely" (e.g., reference counting where there was none
+// before), but it's unclear how to quantify that. Namely, these programs were
+// never written with performance in mind, and evaluating their performance
+// would really be an entirely different exercise.
 //
 // I think a fair conclusion to draw here is that we can largely preserve the
 // character of the original program using safe Rust, but likely cannot do it
@@ -48,6 +50,13 @@ use std::{
     sync::atomic::{AtomicI32, Ordering},
 };
 
+// The original program uses '.' as the concatenation operator in
+// postfix notation, so a '.' wildcard in the pattern can't also be
+// written as '.' there. re2post writes this byte for it instead.
+// It's a NUL byte, which can't otherwise show up in a pattern
+// since command line arguments are NUL terminated.
+const POSTFIX_ANY: u8 = 0;
+
 // Convert infix regexp re to postfix notation.
 // Insert . as explicit concatenation operator.
 // Returns `None` for invalid patterns.
@@ -69,7 +78,8 @@ fn re2post(re: &[u8]) -> Option<Vec<u8>> {
     let (mut nalt, mut natom) = (0, 0);
     let mut paren = vec![];
     let mut dst = vec![];
-    for &byte in re.iter() {
+    let mut it = re.iter();
+    while let Some(&byte) = it.next() {
         match byte {
             b'(' => {
                 if natom > 1 {
@@ -118,17 +128,34 @@ fn re2post(re: &[u8]) -> Option<Vec<u8>> {
                 }
                 dst.push(byte);
             }
-            // Not handled in the original program.
-            // Since '.' is a meta character in the
-            // postfix syntax, it can wreak havoc
-            // if we allow it here.
-            b'.' => return None,
+            // A literal NUL would be mistaken for
+            // a wildcard in the postfix syntax.
+            POSTFIX_ANY => return None,
             _ => {
                 if natom > 1 {
                     natom -= 1;
                     dst.push(b'.');
                 }
-                dst.push(byte);
+                // Not handled in the original program.
+                // Since '.' is a meta character in the
+                // postfix syntax, it would wreak havoc
+                // if we passed it through as is.
+                if byte == b'.' {
+                    dst.push(POSTFIX_ANY);
+                } else if byte == b'\\' {
+                    // A backslash makes the meta character
+                    // after it a literal. It's kept in the
+                    // postfix syntax so that post2nfa also
+                    // knows not to treat it as an operator.
+                    let &escaped = it.next()?;
+                    if !b"\\.*+?|()[]^$-{}".contains(&escaped) {
+                        return None;
+                    }
+                    dst.push(b'\\');
+                    dst.push(escaped);
+                } else {
+                    dst.push(byte);
+                }
                 natom += 1;
             }
         }
@@ -157,9 +184,11 @@ fn re2post(re: &[u8]) -> Option<Vec<u8>> {
 // Represents an NFA state plus zero or one or two arrows exiting.
 // if c == Match, no arrows out; matching state.
 // If c == Split, unlabeled arrows to out and out1 (if != NULL).
+// If c == Any, arrow labeled with every character to out.
 // If c < 256, labeled arrow with character c to out.
 const MATCH: i32 = 256;
 const SPLIT: i32 = 257;
+const ANY: i32 = 258;
 
 struct State {
     c: i32,
@@ -218,6 +247,9 @@ enum PtrList {
     Out1(Rc<RefCell<State>>, Box<PtrList>),
 }
 
+// Lists are passed around boxed, like the
+// pointers they stand in for in the original.
+#[allow(clippy::boxed_local)]
 impl PtrList {
     // Create singleton list that patches parent.out.
     fn out(parent: &Rc<RefCell<State>>) -> Box<PtrList> {
@@ -262,11 +294,41 @@ impl PtrList {
     }
 }
 
+// An NFA is its start state along with every state in it.
+//
+// The original has no need to remember every state, but we do:
+// resetting the list IDs on overflow means visiting each of them, and
+// owning them all is what lets us free the NFA despite its cycles.
+#[allow(clippy::upper_case_acronyms)]
+struct NFA {
+    start: Rc<RefCell<State>>,
+    states: Vec<Rc<RefCell<State>>>,
+}
+
+impl Drop for NFA {
+    fn drop(&mut self) {
+        // Cut every arrow so that no state keeps another alive. This
+        // also means dropping a state never recurses into the states
+        // after it, which could overflow the stack on long chains.
+        for s in self.states.iter() {
+            let mut s = s.borrow_mut();
+            s.out = None;
+            s.out1 = None;
+        }
+    }
+}
+
 // Convert postfix regular expression to NFA.
-// Return start state.
-fn post2nfa(postfix: &[u8]) -> Option<Rc<RefCell<State>>> {
+fn post2nfa(postfix: &[u8]) -> Option<NFA> {
+    let mut states = vec![];
+    let mut new_state = |c, out, out1| {
+        let s = State::new(c, out, out1);
+        states.push(Rc::clone(&s));
+        s
+    };
     let mut stack: Vec<Frag> = vec![];
-    for &p in postfix.iter() {
+    let mut it = postfix.iter();
+    while let Some(&p) = it.next() {
         match p {
             // catenate
             b'.' => {
@@ -279,21 +341,21 @@ fn post2nfa(postfix: &[u8]) -> Option<Rc<RefCell<State>>> {
             b'|' => {
                 let e2 = stack.pop().unwrap();
                 let e1 = stack.pop().unwrap();
-                let s = State::new(SPLIT, Some(e1.start), Some(e2.start));
+                let s = new_state(SPLIT, Some(e1.start), Some(e2.start));
                 let list = PtrList::append(e1.out, e2.out);
                 stack.push(Frag::new(s, list));
             }
             // zero or one
             b'?' => {
                 let e = stack.pop().unwrap();
-                let s = State::new(SPLIT, Some(e.start), None);
+                let s = new_state(SPLIT, Some(e.start), None);
                 let list = PtrList::append(e.out, PtrList::out1(&s));
                 stack.push(Frag::new(s, list));
             }
             // zero or more
             b'*' => {
                 let e = stack.pop().unwrap();
-                let s = State::new(SPLIT, Some(e.start), None);
+                let s = new_state(SPLIT, Some(e.start), None);
                 PtrList::patch(e.out, &s);
                 let list = PtrList::out1(&s);
                 stack.push(Frag::new(s, list));
@@ -301,13 +363,26 @@ fn post2nfa(postfix: &[u8]) -> Option<Rc<RefCell<State>>> {
             // one or more
             b'+' => {
                 let e = stack.pop().unwrap();
-                let s = State::new(SPLIT, Some(e.start.clone()), None);
+                let s = new_state(SPLIT, Some(e.start.clone()), None);
                 PtrList::patch(e.out, &s);
                 let list = PtrList::out1(&s);
                 stack.push(Frag::new(e.start, list));
             }
+            // escaped literal character
+            b'\\' => {
+                let &c = it.next()?;
+                let s = new_state(i32::from(c), None, None);
+                let list = PtrList::out(&s);
+                stack.push(Frag::new(s, list));
+            }
+            // any character
+            POSTFIX_ANY => {
+                let s = new_state(ANY, None, None);
+                let list = PtrList::out(&s);
+                stack.push(Frag::new(s, list));
+            }
             _ => {
-                let s = State::new(i32::from(p), None, None);
+                let s = new_state(i32::from(p), None, None);
                 let list = PtrList::out(&s);
                 stack.push(Frag::new(s, list));
             }
@@ -329,22 +404,49 @@ fn post2nfa(postfix: &[u8]) -> Option<Rc<RefCell<State>>> {
     // time we need it. A bit wasteful, but safely
     // representing a single global match state
     // given our Rc pointers means switching to Arc.
-    PtrList::patch(e.out, &State::new(MATCH, None, None));
-    Some(e.start)
+    PtrList::patch(e.out, &new_state(MATCH, None, None));
+    Some(NFA { start: e.start, states })
 }
 
 struct List {
     s: Box<[Rc<RefCell<State>>]>,
     n: i32,
+    // states that add_state has yet to visit, kept around so that
+    // each call doesn't allocate a new stack
+    stack: Vec<Rc<RefCell<State>>>,
 }
 
 static LIST_ID: AtomicI32 = AtomicI32::new(0);
 
+// Increment to a new list id.
+//
+// The original overflows int if enough searches are run, which is UB.
+// Here, the atomic would just wrap around, but then a stale lastlist
+// could eventually look current again. So on overflow, we reset every
+// state back to the starting condition instead.
+fn increment_list_id(states: &[Rc<RefCell<State>>]) {
+    let id = LIST_ID.load(Ordering::Acquire);
+    let id = match id.checked_add(1) {
+        Some(id) => id,
+        None => {
+            for s in states {
+                s.borrow().lastlist.set(0);
+            }
+            1
+        }
+    };
+    LIST_ID.store(id, Ordering::Release);
+}
+
 impl List {
     // Compute initial state list
-    fn start(&mut self, start: Rc<RefCell<State>>) -> &mut List {
+    fn start(
+        &mut self,
+        start: Rc<RefCell<State>>,
+        states: &[Rc<RefCell<State>>],
+    ) -> &mut List {
         self.n = 0;
-        LIST_ID.fetch_add(1, Ordering::AcqRel);
+        increment_list_id(states);
         self.add_state(Some(&start));
         self
     }
@@ -360,48 +462,62 @@ impl List {
     }
 
     // Add s to l, following unlabeled arrows.
+    //
+    // The original recurses on each split, which can overflow the call
+    // stack on long chains of splits. We use an explicit stack instead,
+    // pushing out1 before out so that states are appended in the same
+    // order as the recursive version.
     fn add_state(&mut self, s: Option<&Rc<RefCell<State>>>) {
-        let Some(s) = s else { return };
-        if s.borrow().lastlist.get() == LIST_ID.load(Ordering::Acquire) {
-            return;
-        }
-        s.borrow().lastlist.set(LIST_ID.load(Ordering::Acquire));
-        if s.borrow().c == SPLIT {
-            // follow unlabeled arrows
-            self.add_state(s.borrow().out.as_ref());
-            self.add_state(s.borrow().out1.as_ref());
-            return;
+        let id = LIST_ID.load(Ordering::Acquire);
+        // Only states that aren't on the list yet are worth a clone of
+        // their handle. A state can still be pushed twice before it's
+        // visited, so it's checked again when it's popped.
+        let fresh = |s: &&Rc<RefCell<State>>| s.borrow().lastlist.get() != id;
+        self.stack.extend(s.filter(fresh).cloned());
+        while let Some(s) = self.stack.pop() {
+            let state = s.borrow();
+            if state.lastlist.get() == id {
+                continue;
+            }
+            state.lastlist.set(id);
+            if state.c == SPLIT {
+                // follow unlabeled arrows
+                self.stack.extend(state.out1.iter().filter(fresh).cloned());
+                self.stack.extend(state.out.iter().filter(fresh).cloned());
+                continue;
+            }
+            drop(state);
+            self.s[self.n as usize] = s;
+            self.n += 1;
         }
-        self.s[self.n as usize] = Rc::clone(s);
-        self.n += 1;
     }
 }
 
 // Step the NFA from the states in clist
 // past the character c,
 // to create next NFA state set nlist.
-fn step(clist: &mut List, c: i32, nlist: &mut List) {
-    LIST_ID.fetch_add(1, Ordering::AcqRel);
+fn step(
+    clist: &mut List,
+    c: i32,
+    nlist: &mut List,
+    states: &[Rc<RefCell<State>>],
+) {
+    increment_list_id(states);
     nlist.n = 0;
     for i in 0..clist.n {
         let s = &clist.s[i as usize];
-        if s.borrow().c == c {
+        if s.borrow().c == c || s.borrow().c == ANY {
             nlist.add_state(s.borrow().out.as_ref());
         }
     }
 }
 
 // Run NFA to determine whether it matches s.
-fn r#match(
-    l1: &mut List,
-    l2: &mut List,
-    start: Rc<RefCell<State>>,
-    s: &[u8],
-) -> bool {
-    let clist = l1.start(start);
+fn r#match(l1: &mut List, l2: &mut List, nfa: &NFA, s: &[u8]) -> bool {
+    let clist = l1.start(Rc::clone(&nfa.start), &nfa.states);
     let nlist = l2;
     for &byte in s.iter() {
-        step(clist, i32::from(byte), nlist);
+        step(clist, i32::from(byte), nlist, &nfa.states);
         std::mem::swap(clist, nlist);
     }
     clist.is_match()
@@ -413,9 +529,18 @@ fn main() -> ExitCode {
         eprintln!("usage: nfa regexp string...");
         return ExitCode::FAILURE;
     }
+    // Debug builds can start the list ID just short of overflowing, so
+    // that the tests can check that it gets reset correctly.
+    #[cfg(debug_assertions)]
+    if let Some(id) = std::env::var_os("NFA_LIST_ID") {
+        let Some(id) = id.to_str().and_then(|id| id.parse().ok()) else {
+            eprintln!("NFA_LIST_ID is not a valid list ID");
+            return ExitCode::FAILURE;
+        };
+        LIST_ID.store(id, Ordering::Release);
+    }
 
-    let Ok(pattern) = argv.by_ref().skip(1).next().unwrap().into_string()
-    else {
+    let Ok(pattern) = argv.by_ref().nth(1).unwrap().into_string() else {
         eprintln!("pattern is invalid UTF-8");
         return ExitCode::FAILURE;
     };
@@ -423,7 +548,7 @@ fn main() -> ExitCode {
         eprintln!("bad regexp {pattern}");
         return ExitCode::FAILURE;
     };
-    let Some(start) = post2nfa(&post) else {
+    let Some(nfa) = post2nfa(&post) else {
         eprintln!("error in post2nfa {pattern}");
         return ExitCode::FAILURE;
     };
@@ -432,17 +557,19 @@ fn main() -> ExitCode {
     let mut l1 = List {
         s: vec![State::new(0, None, None); nstate].into_boxed_slice(),
         n: 0,
+        stack: vec![],
     };
     let mut l2 = List {
         s: vec![State::new(0, None, None); nstate].into_boxed_slice(),
         n: 0,
+        stack: vec![],
     };
     for arg in argv {
         let Ok(haystack) = arg.into_string() else {
             eprintln!("haystack is invalid UTF-8");
             return ExitCode::FAILURE;
         };
-        if r#match(&mut l1, &mut l2, start.clone(), haystack.as_bytes()) {
+        if r#match(&mut l1, &mut l2, &nfa, haystack.as_bytes()) {
             println!("{haystack}");
         }
     }